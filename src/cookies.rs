@@ -0,0 +1,55 @@
+use std::{io, sync::Arc};
+
+use cookie_store::CookieStore;
+use reqwest_cookie_store::CookieStoreMutex;
+use tokio::fs;
+
+use crate::write_file;
+
+/// Loads the cookie jar saved by a previous run, starting with an empty one if there is none.
+/// Expired cookies are dropped.
+pub async fn load(path: Option<&str>) -> Arc<CookieStoreMutex> {
+    let Some(path) = path else {
+        return Arc::default();
+    };
+    let contents = match fs::read(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Arc::default(),
+        Err(err) => {
+            log::warn!("Failed to read cookie file {:?}: {}", path, err);
+            return Arc::default();
+        }
+    };
+
+    match cookie_store::serde::json::load(contents.as_slice()) {
+        Ok(store) => {
+            log::debug!(
+                "Loaded {} cookies from {:?}",
+                store.iter_unexpired().count(),
+                path
+            );
+            Arc::new(CookieStoreMutex::new(store))
+        }
+        Err(err) => {
+            log::warn!("Ignoring cookie file {:?}: {}", path, err);
+            Arc::default()
+        }
+    }
+}
+
+/// Also saves session cookies without an expiry, which usually hold the login.
+pub async fn save(path: &str, jar: &CookieStoreMutex) {
+    let mut contents = Vec::new();
+    let res = {
+        let store: &CookieStore = &jar.lock().unwrap();
+        cookie_store::serde::json::save_incl_expired_and_nonpersistent(store, &mut contents)
+    };
+    if let Err(err) = res {
+        log::error!("Failed to serialize cookies: {}", err);
+        return;
+    }
+
+    if let Err(err) = write_file(path, &contents, true, None, None).await {
+        log::error!("Failed to write cookie file {:?}: {}", path, err);
+    }
+}
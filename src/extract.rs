@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Cursor, Read, Seek},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::sha256_hex;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtractFormat {
+    Zip,
+    TarGz,
+    TarBz2,
+    TarXz,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtractConfig {
+    pub format: ExtractFormat,
+    /// Number of leading path components removed from every file, like `tar --strip-components`.
+    pub strip_components: Option<u32>,
+}
+
+/// Strips leading components from an archived path, rejecting paths that would escape the
+/// destination directory. Returns `None` if nothing is left.
+fn strip(path: &Path, components: u32) -> Result<Option<PathBuf>> {
+    let mut stripped = PathBuf::new();
+    let mut skipped = 0;
+
+    for component in path.components() {
+        match component {
+            Component::Normal(_) if skipped < components => skipped += 1,
+            Component::Normal(part) => stripped.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!("Unsafe path {path:?} in archive")
+            }
+        }
+    }
+
+    Ok((!stripped.as_os_str().is_empty()).then_some(stripped))
+}
+
+/// Calls `f` with the stripped path and contents of every regular file in the archive.
+fn for_each_file<R: Read + Seek>(
+    archive: R,
+    config: &ExtractConfig,
+    mut f: impl FnMut(&Path, &mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    let components = config.strip_components.unwrap_or(0);
+
+    let decoder: Box<dyn Read> = match config.format {
+        ExtractFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(archive).context("Invalid ZIP archive")?;
+            for index in 0..archive.len() {
+                let mut file = archive.by_index(index)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let Some(path) = file.enclosed_name() else {
+                    anyhow::bail!("Unsafe path {:?} in archive", file.name());
+                };
+                if let Some(path) = strip(&path, components)? {
+                    f(&path, &mut file)?;
+                }
+            }
+            return Ok(());
+        }
+        ExtractFormat::TarGz => Box::new(flate2::read::GzDecoder::new(archive)),
+        ExtractFormat::TarBz2 => Box::new(bzip2::read::BzDecoder::new(archive)),
+        ExtractFormat::TarXz => Box::new(lzma_rust2::XzReader::new(archive, true)),
+    };
+
+    for entry in tar::Archive::new(decoder).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if let Some(path) = strip(&path, components)? {
+            f(&path, &mut entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn render_manifest(hashes: &BTreeMap<PathBuf, String>) -> Vec<u8> {
+    hashes
+        .iter()
+        .map(|(path, hash)| format!("{hash}  {}\n", path.display()))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Returns manifests (one `sha256sum` line per file) of the files extracted to `dest` so far and
+/// of the files in the archive, so that changes can be detected per file.
+pub fn manifests(
+    archive: &[u8],
+    dest: &Path,
+    config: &ExtractConfig,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut current = BTreeMap::new();
+    let mut extracted = BTreeMap::new();
+
+    for_each_file(Cursor::new(archive), config, |path, contents| {
+        let mut buf = Vec::new();
+        contents.read_to_end(&mut buf)?;
+        extracted.insert(path.to_path_buf(), sha256_hex(&buf));
+        if let Ok(file) = fs::read(dest.join(path)) {
+            current.insert(path.to_path_buf(), sha256_hex(&file));
+        }
+        Ok(())
+    })?;
+
+    Ok((render_manifest(&current), render_manifest(&extracted)))
+}
+
+/// Writes the archive to `tmp_path` and extracts it into the directory `dest`, replacing
+/// existing files.
+pub fn extract(archive: &[u8], tmp_path: &Path, dest: &Path, config: &ExtractConfig) -> Result<()> {
+    fs::write(tmp_path, archive)
+        .with_context(|| format!("Failed to write archive to {tmp_path:?}"))?;
+
+    let res = File::open(tmp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            for_each_file(file, config, |path, contents| {
+                let target = dest.join(path);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                io::copy(contents, &mut File::create(&target)?)
+                    .with_context(|| format!("Failed to extract {path:?}"))?;
+                Ok(())
+            })
+        });
+    let _ = fs::remove_file(tmp_path);
+
+    res
+}
@@ -0,0 +1,56 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OwnedMutexGuard;
+
+/// Serializes writes to the same path by entries downloading concurrently.
+#[derive(Debug, Default)]
+pub struct WriteLocks {
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Held while writing, releasing both locks when dropped.
+pub struct WriteGuard {
+    _guard: OwnedMutexGuard<()>,
+    _file: Option<File>,
+}
+
+impl WriteLocks {
+    /// Waits until no other download writes to `path`. With `file_lock`, also takes an exclusive
+    /// advisory lock on `{path}.lock`, waiting for other processes holding it.
+    pub async fn lock(&self, path: &Path, file_lock: bool) -> io::Result<WriteGuard> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // Drops the locks of paths not being written, e.g. those with past dates
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(path.to_path_buf()).or_default().clone()
+        };
+        let guard = lock.lock_owned().await;
+
+        let file = if file_lock {
+            let mut lock_path = path.as_os_str().to_os_string();
+            lock_path.push(".lock");
+            // The file is kept, as removing it would let another process lock a new one
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path)?;
+            tokio::task::block_in_place(|| file.lock())?;
+            log::debug!("Locked {:?}", lock_path);
+            Some(file)
+        } else {
+            None
+        };
+
+        Ok(WriteGuard {
+            _guard: guard,
+            _file: file,
+        })
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode, body::Bytes, header, server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{Cache, Config, Status};
+
+#[derive(Clone)]
+struct State {
+    configs: watch::Receiver<Arc<Config>>,
+    cache: Arc<Mutex<Cache>>,
+    status: Arc<Mutex<Status>>,
+}
+
+fn handle(
+    state: &State,
+    req: &Request<hyper::body::Incoming>,
+) -> (StatusCode, &'static str, String) {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => {
+            let interval = state.configs.borrow().interval;
+            if state.status.lock().unwrap().healthy(interval) {
+                (StatusCode::OK, "text/plain", "OK\n".to_string())
+            } else {
+                let body = "No download cycle completed recently\n".to_string();
+                (StatusCode::SERVICE_UNAVAILABLE, "text/plain", body)
+            }
+        }
+        (&Method::GET, "/status") => {
+            let cache = state.cache.lock().unwrap();
+            let body = state.status.lock().unwrap().to_json(&cache).to_string();
+            (StatusCode::OK, "application/json", body)
+        }
+        _ => (StatusCode::NOT_FOUND, "text/plain", String::new()),
+    }
+}
+
+/// Serves the `/healthz` and `/status` endpoints until the future is dropped.
+pub async fn serve(
+    addr: &str,
+    configs: watch::Receiver<Arc<Config>>,
+    cache: Arc<Mutex<Cache>>,
+    status: Arc<Mutex<Status>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind health server to {addr:?}"))?;
+    log::info!("Serving health checks on http://{}/healthz", addr);
+
+    let state = State {
+        configs,
+        cache,
+        status,
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("Failed to accept health connection: {}", err);
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|req| {
+                let (status, content_type, body) = handle(&state, &req);
+                let mut resp = Response::new(Full::new(Bytes::from(body)));
+                *resp.status_mut() = status;
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static(content_type),
+                );
+                async { Ok::<_, Infallible>(resp) }
+            });
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log::debug!("Failed to serve health check to {}: {}", peer, err);
+            }
+        });
+    }
+}
@@ -0,0 +1,56 @@
+use std::{process::Stdio, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+
+fn shell(cmd: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    }
+
+    #[cfg(not(windows))]
+    {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    }
+}
+
+/// Runs a shell command to completion with additional environment variables, killing it once
+/// `timeout` has elapsed. Returns whether the command exited successfully.
+pub async fn run(cmd: &str, env: &[(&str, String)], timeout: Option<Duration>) -> Result<bool> {
+    let child = shell(cmd)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {cmd:?}"))?;
+
+    let output = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, child.wait_with_output())
+            .await
+            .with_context(|| format!("Timed out running {cmd:?} after {timeout:?}"))?,
+        None => child.wait_with_output().await,
+    }
+    .with_context(|| format!("Failed to run {cmd:?}"))?;
+
+    for (name, stream) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        let stream = String::from_utf8_lossy(stream);
+        if !stream.trim().is_empty() {
+            log::debug!("Output of {:?} ({}):\n{}", cmd, name, stream.trim_end());
+        }
+    }
+
+    if output.status.success() {
+        log::info!("Ran {:?} ({})", cmd, output.status);
+    } else {
+        log::error!("Failed to run {:?} ({})", cmd, output.status);
+    }
+
+    Ok(output.status.success())
+}
@@ -0,0 +1,86 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// Log sink appending to a file, with ANSI escape codes stripped and optional size-based rotation.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    in_escape: bool,
+}
+
+impl LogFile {
+    pub fn open(path: impl Into<PathBuf>, max_size: Option<u64>) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            in_escape: false,
+        })
+    }
+
+    /// Renames the current file to `<path>.1` (replacing any previous one) and starts a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+
+        fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+
+    /// Removes `ESC [ ... <letter>` sequences, keeping track of sequences split across writes.
+    fn strip_ansi(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut plain = Vec::with_capacity(buf.len());
+        let mut bytes = buf.iter().copied().peekable();
+
+        while let Some(byte) = bytes.next() {
+            if self.in_escape {
+                self.in_escape = !byte.is_ascii_alphabetic();
+            } else if byte == 0x1b && bytes.peek().is_none_or(|&next| next == b'[') {
+                self.in_escape = true;
+            } else {
+                plain.push(byte);
+            }
+        }
+
+        plain
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let plain = self.strip_ansi(buf);
+        self.file.write_all(&plain)?;
+        self.size += plain.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    /// Called after every record, so rotation never splits a line across files.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if let Some(max_size) = self.max_size
+            && self.size >= max_size
+        {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,196 +1,4006 @@
-use std::{collections::HashMap, env, fs, io, path::Path, process::ExitCode, time::Duration};
+mod cookies;
+mod extract;
+mod file_lock;
+mod health;
+mod hooks;
+mod log_file;
+mod metrics;
+mod oauth2;
+mod pid_file;
+mod rate_limit;
+mod s3;
+#[cfg(windows)]
+mod service;
+mod signals;
+#[cfg(unix)]
+mod syslog_sink;
+mod systemd;
+mod webhook;
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, hash_map},
+    env, fmt, io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{Context, Result};
-use chrono::Local;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{
+    DateTime, FixedOffset, Local, Utc,
+    format::{Item, StrftimeItems},
+};
 use colored::Colorize;
+use encoding_rs::Encoding;
 use fern::{
     Dispatch,
     colors::{Color, ColoredLevelConfig},
 };
 use imara_diff::{Algorithm, Diff, InternedInput};
 use log::LevelFilter;
-use reqwest::{Client, StatusCode, header};
-use serde::Deserialize;
+use lru::LruCache;
+use reqwest::{
+    Certificate, Client, Identity, Method, NoProxy, Proxy, Response, StatusCode, Url,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    redirect,
+};
+use reqwest_cookie_store::CookieStoreMutex;
+use serde::{
+    Deserialize, Deserializer,
+    de::{self, DeserializeOwned},
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Semaphore, oneshot, watch},
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(windows)]
+use crate::service::ServiceCommand;
+#[cfg(unix)]
+use crate::syslog_sink::SyslogSink;
+use crate::{
+    extract::ExtractConfig,
+    file_lock::WriteLocks,
+    log_file::LogFile,
+    oauth2::OAuth2Config,
+    rate_limit::{RateLimitConfig, RateLimiter, Throttle},
+    s3::Presigner,
+    signals::{ReloadSignal, StatusSignal, wait_for_shutdown_signal},
+    webhook::{Event, EventKind, WebhookConfig},
+};
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Args {
+    once: bool,
+    dry_run: bool,
+    check: bool,
+    /// Skips conditional requests, which is reset after the first cycle.
+    force: bool,
+    #[cfg(windows)]
+    service: Option<ServiceCommand>,
+}
 
 #[derive(Debug, Deserialize)]
 struct Config {
     #[serde(with = "humantime_serde")]
     interval: Duration,
+    /// Fraction of the interval (0.0 to 1.0) added as a random delay to each poll.
+    jitter: Option<f64>,
     #[serde(default)]
     create_directories: bool,
+    #[serde(default = "default_true")]
+    atomic_writes: bool,
+    /// Directory for the temporary files of atomic writes instead of next to the destination,
+    /// which should be on the same filesystem for the rename to be atomic.
+    temp_dir: Option<String>,
+    /// Also locks `{path}.lock` while writing, against other instances writing the same files.
+    file_lock: Option<bool>,
+    /// Reads written files back to compare them with the download, e.g. on network filesystems.
+    verify_write: Option<bool>,
+    /// Converts CRLF line endings of text files to LF before comparing and writing them.
+    normalize_line_endings: Option<bool>,
+    /// Appends a newline to text files that don't end with one.
+    ensure_trailing_newline: Option<bool>,
+    /// Copies the previous file to `<path><backup_suffix>` before overwriting it.
+    backup: Option<bool>,
+    /// Suffix of backups, `.bak` by default. May contain `strftime` placeholders like `%Y`.
+    backup_suffix: Option<String>,
+    /// Time zone of `{{date}}` and the other placeholders in URLs and paths.
+    #[serde(default)]
+    template_timezone: TemplateTimezone,
+    /// Downloads all files right away instead of waiting for their first interval.
+    #[serde(default = "default_true")]
+    download_on_startup: bool,
+    /// Only signals readiness to systemd once every entry was downloaded successfully, retrying
+    /// failed ones with the retry backoff. Implies `download_on_startup`.
+    #[serde(default)]
+    bootstrap_required: bool,
+    /// Time after which the process exits if `bootstrap_required` isn't fulfilled yet.
+    #[serde(default, with = "humantime_serde")]
+    bootstrap_timeout: Option<Duration>,
+    /// Time to wait for running downloads on shutdown before cancelling them, `30s` by default.
+    #[serde(default, with = "humantime_serde")]
+    shutdown_timeout: Option<Duration>,
+    /// Maximum number of simultaneous downloads, `1` by default.
+    concurrency: Option<usize>,
+    /// Limits the rate of requests across all downloads, including retries.
+    rate_limit: Option<RateLimitConfig>,
+    /// Maximum throughput of each download in bytes per second.
+    bandwidth_limit_bps: Option<u64>,
+    /// Rejects responses whose body doesn't match their `Content-MD5` header, `true` by default.
+    verify_content_md5: Option<bool>,
+    /// Consecutive failures after which a URL is paused for `failure_cooldown`, `0` (never)
+    /// by default.
+    failure_threshold: Option<u32>,
+    #[serde(default, with = "humantime_serde")]
+    failure_cooldown: Option<Duration>,
+    /// Consecutive failures of a URL after which webhooks with `notify_failures` are notified,
+    /// `1` by default. Later failures of the same outage aren't notified again.
+    failure_alert_after: Option<u32>,
+    max_retries: Option<u32>,
+    #[serde(default = "default_retry_base_delay", with = "humantime_serde")]
+    retry_base_delay: Duration,
+    /// Upper bound of the backoff, `5m` by default.
+    #[serde(default, with = "humantime_serde")]
+    retry_max_delay: Option<Duration>,
+    /// Fraction of the backoff (0.0 to 1.0) added as a random delay, so that instances sharing
+    /// a server don't retry in lockstep.
+    retry_jitter: Option<f64>,
+    /// Response statuses that are retried, `[429, 500, 502, 503, 504]` by default.
+    retry_on_status: Option<Vec<u16>>,
+    /// Total time from sending a request until the full response body has been received.
+    #[serde(default, alias = "timeout", with = "humantime_serde")]
+    request_timeout: Option<Duration>,
+    /// Time allowed for establishing the connection (TCP handshake and TLS).
+    #[serde(default, with = "humantime_serde")]
+    connect_timeout: Option<Duration>,
+    /// Time allowed without receiving any data, which detects stalled downloads of any size.
+    #[serde(default, with = "humantime_serde")]
+    read_timeout: Option<Duration>,
     log_level: LevelFilter,
+    #[serde(default)]
+    diff_algorithm: DiffAlgorithm,
+    /// Logs a unified diff of every changed file at the `debug` level.
+    #[serde(default)]
+    log_diff: bool,
+    /// Unchanged lines shown around each change in logged diffs, `3` by default.
+    context_lines: Option<usize>,
+    /// Lines after which logged diffs are truncated, `50` by default.
+    max_diff_lines: Option<usize>,
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default)]
+    log_target: LogTarget,
+    /// File to append logs to in addition to `log_target`, without colors.
+    log_file: Option<String>,
+    /// Size after which the log file is renamed to `<log_file>.1` and a new one is started.
+    log_file_max_size_mb: Option<u64>,
+    /// Address to serve Prometheus metrics on, e.g. `0.0.0.0:9090`.
+    metrics_addr: Option<String>,
+    /// File the process ID is written to while running as a daemon.
+    pid_file: Option<String>,
+    /// JSON file keeping the cached `ETag` and `Last-Modified` validators across restarts.
+    state_file: Option<String>,
+    /// Maximum number of URLs whose validators are kept, dropping the least recently used ones,
+    /// e.g. for URL templates with dates. Unlimited by default.
+    etag_cache_size: Option<usize>,
+    /// Keeps cookies set by servers, e.g. session cookies from a login, for later requests.
+    cookie_store: Option<bool>,
+    /// JSON file the cookies are loaded from at startup and saved to on shutdown. Implies
+    /// `cookie_store`.
+    cookie_store_path: Option<String>,
+    /// Address to serve the `/healthz` and `/status` endpoints on, e.g. `0.0.0.0:8080`.
+    health_addr: Option<String>,
+    /// Sent with every request unless overridden per entry, `knot-downloader/<version>` by default.
+    user_agent: Option<String>,
+    /// Endpoint for `s3://` URLs on S3-compatible servers like MinIO, e.g. `http://minio:9000`.
+    s3_endpoint: Option<String>,
+    /// Proxy for all requests instead of the `HTTP_PROXY` and `HTTPS_PROXY` variables.
+    proxy: Option<ProxyConfig>,
+    tls: Option<TlsConfig>,
+    /// PEM file (or directory of PEM files) with additional trusted CA certificates.
+    tls_ca_bundle: Option<String>,
+    /// Accepts any server certificate, only allowed when built with the `insecure` feature.
+    tls_insecure: Option<bool>,
+    /// Number of redirects to follow, `10` by default. `0` disables redirects.
+    max_redirects: Option<u32>,
+    /// Notified whenever a file is updated, unless overridden per entry.
+    webhook: Option<WebhookConfig>,
+    /// Notified whenever a URL is downloaded successfully after failing.
+    on_recovery_webhook: Option<WebhookConfig>,
+    /// Time after which `on_change` commands are killed.
+    #[serde(default, with = "humantime_serde")]
+    on_change_timeout: Option<Duration>,
+    #[serde(default)]
+    files: Vec<FileEntry>,
+}
+
+/// Additional config file, of which only the files are used.
+#[derive(Debug, Deserialize)]
+struct ConfigFragment {
     files: Vec<FileEntry>,
 }
 
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ChangeDetection {
+    /// Compares the lines, or the bytes of binary files.
+    #[default]
+    Diff,
+    /// Compares the SHA-256 hashes, which is cheaper for large text files.
+    Hash,
+    /// Writes every download without reading the current file.
+    Always,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TemplateTimezone {
+    #[default]
+    Local,
+    Utc,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DiffAlgorithm {
+    #[default]
+    Histogram,
+    Myers,
+    /// Myers without heuristics, always finding the smallest diff but slow for large files.
+    MyersMinimal,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum HttpVersion {
+    #[serde(rename = "http1")]
+    Http11,
+    /// Without negotiation, so it also works with cleartext `http://` URLs.
+    #[serde(rename = "http2")]
+    Http2,
+}
+
+impl From<DiffAlgorithm> for Algorithm {
+    fn from(algorithm: DiffAlgorithm) -> Self {
+        match algorithm {
+            DiffAlgorithm::Histogram => Algorithm::Histogram,
+            DiffAlgorithm::Myers => Algorithm::Myers,
+            DiffAlgorithm::MyersMinimal => Algorithm::MyersMinimal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ValidateFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogTarget {
+    #[default]
+    Stdout,
+    File(String),
+    Syslog,
+    EventLog,
+}
+
 #[derive(Debug, Deserialize)]
 struct FileEntry {
+    /// The first URL, followed by mirrors that are tried in order whenever the previous one fails.
+    #[serde(alias = "url", deserialize_with = "deserialize_urls")]
+    urls: Vec<String>,
+    /// Files the download is written to. Only the first is compared to detect changes.
+    #[serde(alias = "path", deserialize_with = "deserialize_paths")]
+    paths: Vec<String>,
+    /// Skips the entry without removing it from the config, `true` by default.
+    enabled: Option<bool>,
+    /// Entries with a higher priority are downloaded first, `0` by default.
+    priority: Option<i32>,
+    /// Overrides the global `create_directories` setting.
+    create_directories: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    /// Five-field cron expression (in local time) to poll on instead of an interval.
+    cron: Option<String>,
+    jitter: Option<f64>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+    sha256: Option<String>,
+    /// Stores the response as raw bytes and compares it byte by byte instead of by lines.
+    binary: Option<bool>,
+    /// How to tell whether the file changed, `diff` by default.
+    change_detection: Option<ChangeDetection>,
+    /// Only accepts responses that parse in this format.
+    validate_format: Option<ValidateFormat>,
+    /// Character encoding of the text file, e.g. `iso-8859-1`, which is converted to UTF-8.
+    encoding: Option<String>,
+    /// Decompresses `gzip` and `deflate` responses, `true` by default. Disable to store
+    /// pre-compressed files as-is.
+    decompress: Option<bool>,
+    /// Forces HTTP/1.1 for servers that mishandle HTTP/2, or HTTP/2 for APIs requiring it. By
+    /// default, HTTP/2 is used if the server offers it during the TLS handshake.
+    http_version: Option<HttpVersion>,
+    /// Overrides the global `normalize_line_endings` setting.
+    normalize_line_endings: Option<bool>,
+    /// Overrides the global `ensure_trailing_newline` setting.
+    ensure_trailing_newline: Option<bool>,
+    /// Overrides the global `backup` setting.
+    backup: Option<bool>,
+    /// Additional path at which a symlink to `path` is created.
+    symlink_to: Option<String>,
+    /// File to append the complete diff of every change to.
+    diff_log_path: Option<String>,
+    /// JSON file the response headers of every successful download are written to.
+    save_headers_to: Option<String>,
+    /// Receives binary files into `<path>.part` first and continues an interrupted download
+    /// from there with a `Range` request. Ignored for text files.
+    resumable: Option<bool>,
+    /// Streams the body to a temporary file instead of keeping it in memory, for large files. The
+    /// file is treated as binary and replaced if its SHA-256 differs from the existing one.
+    streaming: Option<bool>,
+    /// Overrides the global `bandwidth_limit_bps`.
+    bandwidth_limit_bps: Option<u64>,
+    /// Extracts the downloaded archive into `path`, which is treated as a directory.
+    extract: Option<ExtractConfig>,
+    /// Unix permission bits of the file, e.g. `0o600`. Kept from the previous file if unset.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    mode: Option<u32>,
+    /// HTTP method of the request, `GET` by default.
+    #[serde(default, deserialize_with = "deserialize_method")]
+    method: Option<Method>,
+    /// Request body, e.g. a GraphQL query sent with `method: POST`.
+    body: Option<String>,
+    /// `Content-Type` header of the `body`.
+    content_type: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    user_agent: Option<String>,
+    /// Overrides the global `proxy`.
+    proxy: Option<ProxyConfig>,
+    /// Connects to this Unix domain socket, e.g. `/var/run/docker.sock`, instead of the host of
+    /// the URL, which is still sent in the `Host` header. Proxies don't apply.
+    unix_socket: Option<String>,
+    /// Overrides the global `tls`.
+    tls: Option<TlsConfig>,
+    /// Trusted in addition to the global `tls_ca_bundle`.
+    tls_ca_bundle: Option<String>,
+    /// Overrides the global `tls_insecure` setting.
+    tls_insecure: Option<bool>,
+    /// Overrides the global `max_redirects`.
+    max_redirects: Option<u32>,
+    /// Overrides the global `max_retries`, e.g. for flaky servers or to fail fast.
+    max_retries: Option<u32>,
+    /// Ignores cached validators once the last full download is older than this, for servers
+    /// that keep their `ETag` although the content changed.
+    #[serde(default, with = "humantime_serde")]
+    max_cache_age: Option<Duration>,
+    auth: Option<BasicAuth>,
+    bearer_token: Option<String>,
+    /// Name of the environment variable holding the bearer token, read before every request.
+    bearer_token_env: Option<String>,
+    /// Fetches bearer tokens with the OAuth2 client credentials grant.
+    oauth2: Option<OAuth2Config>,
+    api_key: Option<ApiKeyConfig>,
+    webhook: Option<WebhookConfig>,
+    /// Shell command to run after the file has been updated, e.g. to reload a service.
+    on_change: Option<String>,
+    /// Shell command to run before every download, which is skipped if the command fails.
+    before_download: Option<String>,
+}
+
+impl FileEntry {
+    /// The primary URL, which identifies the entry in logs.
+    fn url(&self) -> &str {
+        &self.urls[0]
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyConfig {
+    /// Either a literal key or `$NAME` to read it from the environment variable `NAME`.
+    key: String,
+    /// Name of the header to send the key in, e.g. `X-Api-Key`.
+    header: Option<String>,
+    /// Name of the query parameter to send the key in, e.g. `api_key`.
+    query_param: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BasicAuth {
+    username: String,
+    /// Either a literal password or `$NAME` to read it from the environment variable `NAME`.
+    password: String,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ProxyProtocol {
+    Http,
+    Https,
+    Socks5,
+    /// SOCKS5 with DNS resolution by the proxy, which avoids leaking lookups.
+    Socks5h,
+}
+
+impl ProxyProtocol {
+    fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "http" => Some(Self::Http),
+            "https" => Some(Self::Https),
+            "socks5" => Some(Self::Socks5),
+            "socks5h" => Some(Self::Socks5h),
+            _ => None,
+        }
+    }
+
+    fn scheme(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Https => "https",
+            Self::Socks5 => "socks5",
+            Self::Socks5h => "socks5h",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProxyConfig {
+    /// Either a URL like `socks5h://host:port` or just `host:port` together with `protocol`.
     url: String,
-    path: String,
+    /// Replaces the scheme of `url`.
+    protocol: Option<ProxyProtocol>,
+    username: Option<String>,
+    /// Either a literal password or `$NAME` to read it from the environment variable `NAME`.
+    password: Option<String>,
+    /// Hosts, domains or IP ranges to connect to directly.
+    no_proxy: Option<Vec<String>>,
 }
 
-fn setup_logger(level: LevelFilter) -> Result<()> {
-    let colors = ColoredLevelConfig::new()
-        .error(Color::Red)
-        .warn(Color::Yellow)
-        .info(Color::Green)
-        .debug(Color::BrightBlack);
+/// Client certificate for mutual TLS, either from files or inline.
+#[derive(Debug, Deserialize)]
+struct TlsConfig {
+    client_cert_path: Option<String>,
+    /// Private key, unless it is included in the certificate file.
+    client_key_path: Option<String>,
+    /// PEM with the certificate (chain) and private key, instead of `client_cert_path`.
+    client_cert_pem: Option<String>,
+}
 
-    Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "{} {:<5} {}",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                colors.color(record.level()),
-                message,
-            ))
+/// Validators from previous responses used for conditional requests, keyed by URL.
+#[derive(Debug, Deserialize)]
+struct Cache {
+    etags: HashMap<String, String>,
+    last_modified: HashMap<String, String>,
+    /// Time of the last full download, which isn't saved to the `state_file`.
+    #[serde(skip)]
+    downloaded: HashMap<String, Instant>,
+    /// Cached URLs by their last use, for `etag_cache_size`.
+    #[serde(skip, default = "LruCache::unbounded")]
+    recent: LruCache<String, ()>,
+    #[serde(skip)]
+    write_locks: Arc<WriteLocks>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            etags: HashMap::new(),
+            last_modified: HashMap::new(),
+            downloaded: HashMap::new(),
+            recent: LruCache::unbounded(),
+            write_locks: Arc::default(),
+        }
+    }
+}
+
+impl Cache {
+    /// Marks the validators of `url` as used, then drops those of the least recently used URLs
+    /// beyond `capacity`, which are downloaded in full the next time.
+    fn touch(&mut self, url: &str, capacity: Option<usize>) {
+        self.recent.put(url.to_string(), ());
+        let Some(capacity) = capacity else {
+            return;
+        };
+        while self.recent.len() > capacity
+            && let Some((url, ())) = self.recent.pop_lru()
+        {
+            self.etags.remove(&url);
+            self.last_modified.remove(&url);
+            self.downloaded.remove(&url);
+            log::debug!(
+                "Evicted cached validators of {} ({} URLs cached)",
+                redact_url(&url),
+                self.recent.len()
+            );
+        }
+    }
+
+    /// Drops the validators of `url`, so that it is downloaded again next time.
+    fn forget(&mut self, url: &str) {
+        self.etags.remove(url);
+        self.last_modified.remove(url);
+        self.recent.pop(url);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    None,
+    Binary,
+    Text {
+        additions: u32,
+        removals: u32,
+    },
+    /// Detected without comparing the contents, see `ChangeDetection`.
+    Unknown,
+}
+
+impl Change {
+    fn counts(self) -> (u32, u32) {
+        match self {
+            Change::Text {
+                additions,
+                removals,
+            } => (additions, removals),
+            Change::None | Change::Binary | Change::Unknown => (0, 0),
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::None | Change::Binary => Ok(()),
+            Change::Unknown => write!(f, ", changed"),
+            Change::Text {
+                additions,
+                removals,
+            } => write!(
+                f,
+                ", {}/{}",
+                format!("+{additions}").green(),
+                format!("-{removals}").red(),
+            ),
+        }
+    }
+}
+
+/// Consecutive failures of a URL, which is paused (open) once `failure_threshold` is reached.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    failures: u32,
+    /// End of the cooldown once open, kept across reloads
+    open_until: Option<Instant>,
+}
+
+/// Results of the latest downloads of a URL, for status dumps.
+#[derive(Debug, Default)]
+struct UrlStatus {
+    last_success: Option<DateTime<Local>>,
+    last_failure: Option<DateTime<Local>>,
+    consecutive_failures: u32,
+}
+
+/// Runtime state dumped on `SIGUSR1` and served on `/status`.
+#[derive(Debug)]
+struct Status {
+    started: Instant,
+    cycles: u64,
+    last_cycle: Option<Instant>,
+    urls: HashMap<String, UrlStatus>,
+}
+
+impl Status {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            cycles: 0,
+            last_cycle: None,
+            urls: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of consecutive failures of the URL before this outcome.
+    fn record(&mut self, url: &str, outcome: Outcome) -> u32 {
+        let status = self.urls.entry(url.to_string()).or_default();
+        let failures_before = status.consecutive_failures;
+        match outcome {
+            Outcome::Failed => {
+                status.last_failure = Some(Local::now());
+                status.consecutive_failures += 1;
+            }
+            Outcome::Updated | Outcome::Unchanged => {
+                status.last_success = Some(Local::now());
+                status.consecutive_failures = 0;
+            }
+            Outcome::Skipped => {}
+        }
+
+        failures_before
+    }
+
+    /// Whether a cycle completed (or the process started) within twice the interval.
+    fn healthy(&self, interval: Duration) -> bool {
+        self.last_cycle.unwrap_or(self.started).elapsed() <= interval * 2
+    }
+
+    /// Renders the status together with the cached validators of every URL, which also
+    /// includes mirrors.
+    fn to_json(&self, cache: &Cache) -> serde_json::Value {
+        let urls: HashSet<_> = self
+            .urls
+            .keys()
+            .chain(cache.etags.keys())
+            .chain(cache.last_modified.keys())
+            .collect();
+        let timestamp =
+            |time: Option<DateTime<Local>>| time.map(|time| time.format("%+").to_string());
+
+        let urls: serde_json::Map<_, _> = urls
+            .into_iter()
+            .map(|url| {
+                let status = self.urls.get(url);
+                let value = serde_json::json!({
+                    "etag": cache.etags.get(url),
+                    "last_modified": cache.last_modified.get(url),
+                    "last_success": timestamp(status.and_then(|status| status.last_success)),
+                    "last_failure": timestamp(status.and_then(|status| status.last_failure)),
+                    "consecutive_failures": status.map_or(0, |status| status.consecutive_failures),
+                });
+                (redact_url(url), value)
+            })
+            .collect();
+
+        serde_json::json!({
+            "cycle_count": self.cycles,
+            "uptime_seconds": self.started.elapsed().as_secs(),
+            "urls": urls,
         })
-        .filter(|metadata| metadata.target() == module_path!())
-        .level(level)
-        .chain(io::stdout())
-        .apply()
-        .context("Failed to setup logger")?;
+    }
+}
 
-    Ok(())
+/// Summary of the downloads in one cycle.
+#[derive(Debug, Default)]
+struct CycleStats {
+    checked: usize,
+    updated: usize,
+    unchanged: usize,
+    failed: usize,
+    skipped: usize,
+    bytes: usize,
 }
 
-async fn wait_for_shutdown_signal() -> Result<()> {
-    #[cfg(unix)]
-    {
-        use tokio::signal::unix::{SignalKind, signal};
+impl CycleStats {
+    fn record(&mut self, outcome: Outcome, bytes: usize) {
+        self.checked += 1;
+        self.bytes += bytes;
+        match outcome {
+            Outcome::Updated => self.updated += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::Skipped => self.skipped += 1,
+        }
+    }
+}
+
+impl fmt::Display for CycleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checked {} files ({} changed, {} unchanged, {} failed, ",
+            self.checked, self.updated, self.unchanged, self.failed,
+        )?;
+        if self.skipped > 0 {
+            write!(f, "{} skipped, ", self.skipped)?;
+        }
+        write!(
+            f,
+            "{} downloaded)",
+            human_bytes::human_bytes(self.bytes as f64)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Updated,
+    Unchanged,
+    Failed,
+    /// Not downloaded because `before_download` failed.
+    Skipped,
+}
 
-        let mut sigterm =
-            signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
-        let mut sigint =
-            signal(SignalKind::interrupt()).context("Failed to install SIGINT handler")?;
+/// Format version of the `state_file`, files with other versions are ignored.
+const STATE_VERSION: u64 = 1;
 
-        tokio::select! {
-            _ = sigterm.recv() => {},
-            _ = sigint.recv() => {},
+const DEFAULT_RETRY_ON_STATUS: [u16; 5] = [429, 500, 502, 503, 504];
+
+const DEFAULT_RETRY_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Writes whose content doesn't match when read back (`verify_write`) are attempted this often.
+const WRITE_ATTEMPTS: u32 = 3;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_FAILURE_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_retry_base_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Accepts a single string or a non-empty list of strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_urls<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => Ok(vec![url]),
+        OneOrMany::Many(urls) if urls.is_empty() => {
+            Err(de::Error::custom("at least one URL is required"))
+        }
+        OneOrMany::Many(urls) => Ok(urls),
+    }
+}
+
+fn deserialize_paths<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => Ok(vec![path]),
+        OneOrMany::Many(paths) if paths.is_empty() => {
+            Err(de::Error::custom("at least one path is required"))
+        }
+        OneOrMany::Many(paths) => Ok(paths),
+    }
+}
+
+/// Accepts file modes as integers or as octal strings like `"0o600"` or `"600"`.
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        Octal(String),
+    }
+
+    let mode = match Option::<Mode>::deserialize(deserializer)? {
+        Some(Mode::Number(mode)) => mode,
+        Some(Mode::Octal(mode)) => {
+            let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+            u32::from_str_radix(digits, 8)
+                .map_err(|_| de::Error::custom(format!("invalid file mode {mode:?}")))?
         }
+        None => return Ok(None),
+    };
+    if mode > 0o7777 {
+        return Err(de::Error::custom(format!("invalid file mode {mode:#o}")));
     }
 
+    Ok(Some(mode))
+}
+
+fn deserialize_method<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Method>, D::Error> {
+    let Some(method) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    Method::from_bytes(method.to_uppercase().as_bytes())
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("invalid HTTP method {method:?}")))
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = pico_args::Arguments::from_env();
+
     #[cfg(windows)]
-    {
-        use tokio::signal::windows;
+    let service = match args.subcommand()?.as_deref() {
+        Some("service") => Some(args.free_from_str()?),
+        Some(command) => anyhow::bail!("Unknown command {command:?}"),
+        None => None,
+    };
 
-        let mut ctrl_c = windows::ctrl_c().context("Failed to install Ctrl-C handler")?;
-        let mut ctrl_break =
-            windows::ctrl_break().context("Failed to install Ctrl-Break handler")?;
-        let mut ctrl_close =
-            windows::ctrl_close().context("Failed to install Ctrl-Close handler")?;
-        let mut ctrl_shutdown =
-            windows::ctrl_shutdown().context("Failed to install Ctrl-Shutdown handler")?;
+    let parsed = Args {
+        once: args.contains("--once"),
+        dry_run: args.contains("--dry-run"),
+        check: args.contains("--check"),
+        force: args.contains("--force"),
+        #[cfg(windows)]
+        service,
+    };
 
-        tokio::select! {
-            _ = ctrl_c.recv() => {},
-            _ = ctrl_break.recv() => {},
-            _ = ctrl_close.recv() => {},
-            _ = ctrl_shutdown.recv() => {},
+    let remaining = args.finish();
+    if !remaining.is_empty() {
+        anyhow::bail!("Unexpected arguments: {remaining:?}");
+    }
+
+    Ok(parsed)
+}
+
+/// Replaces `${NAME}` with the value of the environment variable `NAME`; `$${` produces a
+/// literal `${`.
+fn expand_env(contents: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(contents.len());
+    let mut rest = contents;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .context("Unterminated environment variable reference")?;
+            let name = &reference[..end];
+            let value = env::var(name)
+                .with_context(|| format!("Failed to read environment variable {name:?}"))?;
+            expanded.push_str(&value);
+            rest = &reference[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
         }
     }
+    expanded.push_str(rest);
 
-    Ok(())
+    Ok(expanded)
 }
 
-async fn download_files(files: &[FileEntry], interval: Duration) -> Result<()> {
-    let client = Client::new();
-    let mut etags = HashMap::new();
+fn parse_config<T: DeserializeOwned>(path: &Path, contents: &str) -> Result<T> {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    let contents = &expand_env(contents)?;
 
-    loop {
-        for FileEntry { url, path } in files {
-            let mut req = client.get(url);
-            if let Some(etag) = etags.get(url) {
-                req = req.header(header::IF_NONE_MATCH, etag);
-            }
+    let config = match extension {
+        #[cfg(feature = "toml-config")]
+        Some("toml") => toml::from_str(contents)?,
+        #[cfg(not(feature = "toml-config"))]
+        Some("toml") => {
+            anyhow::bail!("TOML support is disabled (enable the `toml-config` feature)")
+        }
+        Some("json") => serde_json::from_str(contents)?,
+        // Unknown extensions are treated as YAML for backward compatibility
+        _ => serde_yaml::from_str(contents)?,
+    };
 
-            match req.send().await {
-                Ok(resp) if resp.status().is_success() => {
-                    if let Some(etag) = resp.headers().get(header::ETAG) {
-                        etags.insert(url, etag.to_str().unwrap().to_string());
-                    }
+    Ok(config)
+}
 
-                    let body = resp
-                        .text()
-                        .await
-                        .with_context(|| format!("Failed to read response body from {url:?}"))?;
-                    let body_len = human_bytes::human_bytes(body.len() as f64);
+/// Checks everything deserialization doesn't, returning all problems found. Makes no requests.
+fn validate_config(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
 
-                    let current = fs::read_to_string(path).unwrap_or_default();
-                    let input = InternedInput::new(current.as_str(), body.as_str());
-                    let diff = Diff::compute(Algorithm::Histogram, &input);
+    if let Some(temp_dir) = &config.temp_dir
+        && !Path::new(temp_dir).is_dir()
+    {
+        errors.push(format!("`temp_dir` {temp_dir:?} is not a directory"));
+    }
 
-                    if diff.count_additions() > 0 || diff.count_removals() > 0 {
-                        fs::write(path, body)
-                            .with_context(|| format!("Failed to write file to {path:?}"))?;
+    let is_fraction = |value: &f64| (0.0..=1.0).contains(value);
+    if !config.jitter.iter().all(is_fraction) {
+        errors.push("`jitter` must be between 0.0 and 1.0".to_string());
+    }
+    if !config.retry_jitter.iter().all(is_fraction) {
+        errors.push("`retry_jitter` must be between 0.0 and 1.0".to_string());
+    }
 
-                        log::info!(
-                            "Downloaded {} to {} ({}, {}/{})",
-                            url,
-                            path,
-                            body_len,
-                            format!("+{}", diff.count_additions()).green(),
-                            format!("-{}", diff.count_removals()).red(),
-                        );
-                    } else {
-                        log::debug!("Skipped {} (no changes)", url);
-                    }
+    if let Some(webhook) = &config.webhook
+        && let Err(err) = validate_webhook(webhook)
+    {
+        errors.push(format!("Invalid webhook: {err:#}"));
+    }
+    if let Some(webhook) = &config.on_recovery_webhook
+        && let Err(err) = validate_webhook(webhook)
+    {
+        errors.push(format!("Invalid `on_recovery_webhook`: {err:#}"));
+    }
+
+    if let Some(rate_limit) = &config.rate_limit
+        && !(rate_limit.requests_per_second.is_finite() && rate_limit.requests_per_second > 0.0)
+    {
+        errors.push("`rate_limit.requests_per_second` must be positive".to_string());
+    }
+    if config.failure_alert_after == Some(0) {
+        errors.push("`failure_alert_after` must be at least 1".to_string());
+    }
+    if config.bandwidth_limit_bps == Some(0) {
+        errors.push("`bandwidth_limit_bps` must be positive".to_string());
+    }
+    if config.etag_cache_size == Some(0) {
+        errors.push("`etag_cache_size` must be positive".to_string());
+    }
+
+    for status in retry_on_status(config) {
+        if !(400..600).contains(status) {
+            errors.push(format!(
+                "`retry_on_status` must only contain error statuses, not {status}"
+            ));
+        }
+    }
+
+    if let Some(user_agent) = &config.user_agent
+        && HeaderValue::from_str(user_agent).is_err()
+    {
+        errors.push(format!("Invalid `user_agent` {user_agent:?}"));
+    }
+
+    if let Some(proxy) = &config.proxy
+        && let Err(err) = build_proxy(proxy)
+    {
+        errors.push(format!("Invalid proxy: {err:#}"));
+    }
+
+    if let Some(tls) = &config.tls
+        && let Err(err) = load_identity(tls)
+    {
+        errors.push(format!("Invalid TLS config: {err:#}"));
+    }
+
+    if let Some(path) = &config.tls_ca_bundle
+        && let Err(err) = load_ca_certificates(path)
+    {
+        errors.push(format!("Invalid `tls_ca_bundle`: {err:#}"));
+    }
+
+    if let Some(suffix) = &config.backup_suffix
+        && StrftimeItems::new(suffix).any(|item| item == Item::Error)
+    {
+        errors.push(format!("Invalid `backup_suffix` {suffix:?}"));
+    }
+
+    if config.tls_insecure == Some(true) && !cfg!(feature = "insecure") {
+        errors.push("`tls_insecure` requires building with the `insecure` feature".to_string());
+    }
+
+    for entry @ FileEntry {
+        urls,
+        paths,
+        interval,
+        cron,
+        create_directories,
+        method,
+        content_type,
+        headers,
+        user_agent,
+        encoding,
+        proxy,
+        tls,
+        tls_ca_bundle,
+        tls_insecure,
+        unix_socket,
+        auth,
+        bearer_token,
+        bearer_token_env,
+        oauth2,
+        api_key,
+        jitter,
+        webhook,
+        streaming,
+        resumable,
+        extract,
+        validate_format,
+        bandwidth_limit_bps,
+        ..
+    } in &config.files
+    {
+        let url = redact_url(entry.url());
+
+        for raw_url in urls {
+            if let Err(err) = Url::parse(raw_url) {
+                errors.push(format!("Invalid URL {:?}: {}", redact_url(raw_url), err));
+            } else if s3::is_s3_url(raw_url) {
+                if let Err(err) = s3::parse_url(raw_url) {
+                    errors.push(format!("Invalid URL {:?}: {}", redact_url(raw_url), err));
+                }
+                if method.as_ref().is_some_and(|method| method != Method::GET) {
+                    errors.push(format!(
+                        "Only `method: GET` is supported for {:?}",
+                        redact_url(raw_url)
+                    ));
                 }
-                Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED => {
-                    log::debug!("Skipped {} (not modified)", url)
+                if !cfg!(feature = "s3") {
+                    errors.push(format!(
+                        "Can't download {raw_url:?}: S3 support is disabled (enable the `s3` feature)"
+                    ));
                 }
-                Ok(resp) => log::error!("Failed to download {}: {}", url, resp.status()),
-                Err(err) => log::error!("Failed to download {}: {}", url, err),
             }
         }
 
-        tokio::time::sleep(interval).await;
-    }
-}
+        for path in paths.iter().filter(|path| !path.contains("{{")) {
+            if !create_directories.unwrap_or(config.create_directories)
+                && let Some(parent) = Path::new(path).parent()
+                && !parent.as_os_str().is_empty()
+                && !parent.is_dir()
+            {
+                errors.push(format!(
+                    "Directory {parent:?} for {path:?} doesn't exist and `create_directories` is off"
+                ));
+            }
+        }
 
-async fn run() -> Result<()> {
-    let config_path = env::var("CONFIG_PATH").unwrap_or("config.yml".to_string());
-    let config = fs::read_to_string(&config_path)
-        .with_context(|| format!("Failed to read config file from {config_path:?}"))?;
-    let config: Config = serde_yaml::from_str(&config)
-        .with_context(|| format!("Failed to parse config file from {config_path:?}"))?;
+        if let Some(cron) = cron {
+            if interval.is_some() {
+                errors.push(format!(
+                    "Only one of `cron` and `interval` can be set for {url:?}"
+                ));
+            }
+            if let Err(err) = until_next_run(cron) {
+                errors.push(format!("{err:#} for {url:?}"));
+            }
+        }
 
-    setup_logger(config.log_level)?;
+        if !jitter.iter().all(is_fraction) {
+            errors.push(format!("`jitter` must be between 0.0 and 1.0 for {url:?}"));
+        }
 
-    if config.create_directories {
-        for FileEntry { url: _, path } in &config.files {
-            if let Some(parent) = Path::new(&path).parent() {
-                fs::create_dir_all(parent)
-                    .with_context(|| format!("Failed to create directories for {path:?}"))?;
+        if *bandwidth_limit_bps == Some(0) {
+            errors.push(format!(
+                "`bandwidth_limit_bps` must be positive for {url:?}"
+            ));
+        }
+
+        if *streaming == Some(true) {
+            for (option, set) in [
+                ("resumable", *resumable == Some(true)),
+                ("extract", extract.is_some()),
+                ("validate_format", validate_format.is_some()),
+            ] {
+                if set {
+                    errors.push(format!(
+                        "`streaming` can't be combined with `{option}` for {url:?}"
+                    ));
+                }
             }
         }
-    }
 
-    loop {
-        tokio::select! {
-            res = download_files(&config.files, config.interval) => { res? }
-            res = wait_for_shutdown_signal() => {
-                res?;
-                log::warn!("Shutting down...");
-                break;
+        if let Some(headers) = headers
+            && let Err(err) = validate_headers(headers)
+        {
+            errors.push(format!("Invalid headers for {url:?}: {err:#}"));
+        }
+        if let Some(encoding) = encoding
+            && Encoding::for_label(encoding.as_bytes()).is_none()
+        {
+            errors.push(format!("Unknown `encoding` {encoding:?} for {url:?}"));
+        }
+        if let Some(user_agent) = user_agent
+            && HeaderValue::from_str(user_agent).is_err()
+        {
+            errors.push(format!("Invalid `user_agent` {user_agent:?} for {url:?}"));
+        }
+        if let Some(content_type) = content_type
+            && HeaderValue::from_str(content_type).is_err()
+        {
+            errors.push(format!(
+                "Invalid `content_type` {content_type:?} for {url:?}"
+            ));
+        }
+        if let Some(webhook) = webhook
+            && let Err(err) = validate_webhook(webhook)
+        {
+            errors.push(format!("Invalid webhook for {url:?}: {err:#}"));
+        }
+        if let Some(proxy) = proxy
+            && let Err(err) = build_proxy(proxy)
+        {
+            errors.push(format!("Invalid proxy for {url:?}: {err:#}"));
+        }
+        if unix_socket.is_some() {
+            if !cfg!(unix) {
+                errors.push(format!(
+                    "`unix_socket` for {url:?} is only supported on Unix"
+                ));
+            }
+            if proxy.is_some() {
+                errors.push(format!(
+                    "`unix_socket` can't be combined with `proxy` for {url:?}"
+                ));
+            }
+            if urls.iter().any(|url| s3::is_s3_url(url)) {
+                errors.push(format!(
+                    "`unix_socket` can't be used with `s3://` URLs for {url:?}"
+                ));
             }
         }
-    }
+        if let Some(tls) = tls
+            && let Err(err) = load_identity(tls)
+        {
+            errors.push(format!("Invalid TLS config for {url:?}: {err:#}"));
+        }
+        if let Some(path) = tls_ca_bundle
+            && let Err(err) = load_ca_certificates(path)
+        {
+            errors.push(format!("Invalid `tls_ca_bundle` for {url:?}: {err:#}"));
+        }
 
-    Ok(())
-}
+        if *tls_insecure == Some(true) && !cfg!(feature = "insecure") {
+            errors.push(format!(
+                "`tls_insecure` for {url:?} requires building with the `insecure` feature"
+            ));
+        }
 
-#[tokio::main]
-async fn main() -> ExitCode {
-    // Force colored output in Docker environments
-    colored::control::set_override(true);
+        if let Some(auth) = auth
+            && let Err(err) = resolve_secret(&auth.password)
+        {
+            errors.push(format!("Invalid password for {url:?}: {err:#}"));
+        }
 
-    if let Err(err) = run().await {
-        eprintln!("Error: {err}\n\nCaused by:");
-        for cause in err.chain().skip(1) {
-            eprintln!("  {cause}");
+        if let Some(oauth2) = oauth2 {
+            if let Err(err) = Url::parse(&oauth2.token_url) {
+                errors.push(format!("Invalid OAuth2 `token_url` for {url:?}: {err}"));
+            }
+            if let Err(err) = resolve_secret(&oauth2.client_secret) {
+                errors.push(format!(
+                    "Invalid OAuth2 `client_secret` for {url:?}: {err:#}"
+                ));
+            }
         }
-        return ExitCode::from(1);
-    }
 
-    ExitCode::SUCCESS
+        if let Some(api_key) = api_key {
+            match (&api_key.header, &api_key.query_param) {
+                (Some(header), None) => {
+                    if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                        errors.push(format!(
+                            "Invalid `api_key.header` {header:?} for {url:?}"
+                        ));
+                    }
+                }
+                (None, Some(_)) => {}
+                _ => errors.push(format!(
+                    "Exactly one of `api_key.header` and `api_key.query_param` must be set for {url:?}"
+                )),
+            }
+            if let Err(err) = resolve_secret(&api_key.key) {
+                errors.push(format!("Invalid API key for {url:?}: {err:#}"));
+            }
+        }
+
+        let auth_methods = [
+            auth.is_some(),
+            bearer_token.is_some(),
+            bearer_token_env.is_some(),
+            oauth2.is_some(),
+        ];
+        if auth_methods.into_iter().filter(|&set| set).count() > 1 {
+            errors.push(format!(
+                "Only one of `auth`, `bearer_token`, `bearer_token_env` and `oauth2` can be set for {url:?}"
+            ));
+        }
+    }
+
+    errors
+}
+
+fn validate_headers(headers: &HashMap<String, String>) -> Result<()> {
+    for (name, value) in headers {
+        HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name {name:?}"))?;
+        HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value for header {name:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn validate_webhook(webhook: &WebhookConfig) -> Result<()> {
+    Url::parse(&webhook.url).with_context(|| format!("Invalid URL {:?}", webhook.url))?;
+    webhook.method()?;
+    webhook.validate_template()?;
+    if let Some(headers) = &webhook.headers {
+        validate_headers(headers)?;
+    }
+    if let Some(content_type) = &webhook.content_type {
+        HeaderValue::from_str(content_type)
+            .with_context(|| format!("Invalid `content_type` {content_type:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Resolves `$NAME` references to the value of the environment variable `NAME`.
+fn resolve_secret(value: &str) -> Result<String> {
+    match value.strip_prefix('$') {
+        Some(name) => {
+            env::var(name).with_context(|| format!("Failed to read environment variable {name:?}"))
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+fn is_sensitive_param(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["key", "password", "secret", "token"]
+        .iter()
+        .any(|pattern| name.contains(pattern))
+}
+
+/// Masks the password and sensitive query parameters (like `api_key`) of a URL with `***` so
+/// that it can be safely logged.
+fn redact_url(url: &str) -> String {
+    let Ok(mut url) = Url::parse(url) else {
+        return url.to_string();
+    };
+
+    if url.password().is_some() {
+        let _ = url.set_password(Some("***"));
+    }
+
+    if url.query_pairs().any(|(name, _)| is_sensitive_param(&name)) {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| {
+                let value = if is_sensitive_param(&name) {
+                    "***".to_string()
+                } else {
+                    value.into_owned()
+                };
+                (name.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    url.to_string()
+}
+
+fn is_sensitive_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["auth", "cookie", "key", "password", "secret", "token"]
+        .iter()
+        .any(|pattern| name.contains(pattern))
+}
+
+fn format_headers(headers: &HashMap<String, String>) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive_header(name) {
+                "***"
+            } else {
+                value
+            };
+            format!("{name}: {value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Response headers as a JSON object, with arrays for headers that occur multiple times.
+fn headers_json(headers: &HeaderMap) -> serde_json::Value {
+    let headers = headers
+        .keys()
+        .map(|name| {
+            let mut values: Vec<_> = headers
+                .get_all(name)
+                .iter()
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .collect();
+            let value = match values.len() {
+                1 => values.remove(0).into(),
+                _ => values.into(),
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+
+    serde_json::Value::Object(headers)
+}
+
+fn setup_logger(config: &Config) -> Result<()> {
+    let colors = ColoredLevelConfig::new()
+        .error(Color::Red)
+        .warn(Color::Yellow)
+        .info(Color::Green)
+        .debug(Color::BrightBlack);
+
+    // Colors are stripped from log files and not interpreted by syslog
+    let colored_output = matches!(config.log_target, LogTarget::Stdout);
+    if !colored_output {
+        colored::control::set_override(false);
+    }
+
+    let dispatch = match config.log_format {
+        LogFormat::Text => Dispatch::new().format(move |out, message, record| {
+            let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+            if colored_output {
+                out.finish(format_args!(
+                    "{} {:<5} {}",
+                    timestamp,
+                    colors.color(record.level()),
+                    message,
+                ))
+            } else {
+                out.finish(format_args!(
+                    "{} {:<5} {}",
+                    timestamp,
+                    record.level(),
+                    message
+                ))
+            }
+        }),
+        LogFormat::Json => {
+            // Log collectors don't interpret ANSI escape codes
+            colored::control::set_override(false);
+
+            Dispatch::new().format(|out, message, record| {
+                let entry = serde_json::json!({
+                    "timestamp": Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+                    "level": record.level().as_str(),
+                    "message": message.to_string(),
+                    "target": record.target(),
+                });
+                out.finish(format_args!("{entry}"))
+            })
+        }
+    };
+
+    let mut dispatch = dispatch
+        .filter(|metadata| metadata.target().starts_with(module_path!()))
+        .level(config.log_level);
+
+    let max_size = config.log_file_max_size_mb.map(|mb| mb * 1024 * 1024);
+    dispatch = match &config.log_target {
+        LogTarget::Stdout => dispatch.chain(io::stdout()),
+        LogTarget::File(path) => {
+            let file = LogFile::open(path, max_size)
+                .with_context(|| format!("Failed to open log file {path:?}"))?;
+            dispatch.chain(Box::new(file) as Box<dyn io::Write + Send>)
+        }
+        #[cfg(unix)]
+        LogTarget::Syslog => {
+            let sink = SyslogSink::new().context("Failed to connect to syslog")?;
+            dispatch.chain(Box::new(sink) as Box<dyn log::Log>)
+        }
+        #[cfg(not(unix))]
+        LogTarget::Syslog => anyhow::bail!("Logging to syslog is only supported on Unix"),
+        #[cfg(windows)]
+        LogTarget::EventLog => {
+            let sink = eventlog::EventLog::new(service::SERVICE_NAME, log::Level::Trace)
+                .context("Failed to open the Event Log")?;
+            dispatch.chain(Box::new(sink) as Box<dyn log::Log>)
+        }
+        #[cfg(not(windows))]
+        LogTarget::EventLog => {
+            anyhow::bail!("Logging to the Event Log is only supported on Windows")
+        }
+    };
+
+    if let Some(path) = &config.log_file {
+        let file = LogFile::open(path, max_size)
+            .with_context(|| format!("Failed to open log file {path:?}"))?;
+        dispatch = dispatch.chain(Box::new(file) as Box<dyn io::Write + Send>);
+    }
+
+    dispatch.apply().context("Failed to setup logger")?;
+
+    Ok(())
+}
+
+/// Formats a `diff -u` style timestamp, as `patch` expects it in the file headers.
+fn diff_timestamp(time: DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S%.9f %z").to_string()
+}
+
+/// Formats an RFC 3339 timestamp in UTC for `diff_log_path`, which is easier to search and sort
+/// in archives than the local `diff -u` format, and still accepted by `patch`.
+fn diff_log_timestamp(time: DateTime<Local>) -> String {
+    time.to_utc().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Renders the changes as a unified diff that can be applied with `patch -p0`, with the
+/// `timestamps` of the old and new file in the headers.
+fn format_unified_diff(
+    old: &str,
+    new: &str,
+    path: &str,
+    context: usize,
+    algorithm: DiffAlgorithm,
+    timestamps: (&str, &str),
+) -> String {
+    let input = InternedInput::new(old, new);
+    let mut diff = Diff::compute(algorithm.into(), &input);
+    diff.postprocess_lines(&input);
+    let hunks: Vec<_> = diff.hunks().collect();
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let (old_timestamp, new_timestamp) = timestamps;
+    let mut out = format!("--- {path}\t{old_timestamp}\n+++ {path}\t{new_timestamp}\n");
+    let push_line = |out: &mut String, prefix: char, token| {
+        let line: &str = input.interner[token];
+        out.push(prefix);
+        out.push_str(line);
+        if !line.ends_with('\n') {
+            out.push_str("\n\\ No newline at end of file\n");
+        }
+    };
+    // Empty ranges start at the line before them, like in `diff -u`
+    let range = |start: usize, len: usize| format!("{},{len}", start + usize::from(len > 0));
+
+    let context = context as u32;
+    let mut hunks = hunks.into_iter().peekable();
+    while let Some(first) = hunks.next() {
+        // Hunks whose context would overlap are printed together
+        let mut group = vec![first];
+        while let Some(next) = hunks
+            .next_if(|next| next.before.start - group.last().unwrap().before.end <= 2 * context)
+        {
+            group.push(next);
+        }
+
+        let (first, last) = (&group[0], &group[group.len() - 1]);
+        let leading = first.before.start.min(context);
+        let trailing = (input.before.len() as u32 - last.before.end).min(context);
+        let before = (first.before.start - leading) as usize..(last.before.end + trailing) as usize;
+        let after = (first.after.start - leading) as usize..(last.after.end + trailing) as usize;
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(before.start, before.len()),
+            range(after.start, after.len())
+        ));
+
+        let mut pos = before.start;
+        for hunk in &group {
+            for &token in &input.before[pos..hunk.before.start as usize] {
+                push_line(&mut out, ' ', token);
+            }
+            for &token in &input.before[hunk.before.start as usize..hunk.before.end as usize] {
+                push_line(&mut out, '-', token);
+            }
+            for &token in &input.after[hunk.after.start as usize..hunk.after.end as usize] {
+                push_line(&mut out, '+', token);
+            }
+            pos = hunk.before.end as usize;
+        }
+        for &token in &input.before[pos..before.end] {
+            push_line(&mut out, ' ', token);
+        }
+    }
+
+    out
+}
+
+/// Renders the changes as a unified diff for `path`, using the configured context and algorithm.
+/// `modified` is the modification time of the current file, formatted with `timestamp`.
+fn unified_diff(
+    path: &str,
+    current: &[u8],
+    body: &[u8],
+    modified: DateTime<Local>,
+    timestamp: fn(DateTime<Local>) -> String,
+    config: &Config,
+) -> String {
+    format_unified_diff(
+        &String::from_utf8_lossy(current),
+        &String::from_utf8_lossy(body),
+        path,
+        config.context_lines.unwrap_or(3),
+        config.diff_algorithm,
+        (&timestamp(modified), &timestamp(Local::now())),
+    )
+}
+
+/// Renders the diff to log, truncated to `max_diff_lines`.
+fn render_diff(
+    path: &str,
+    current: &[u8],
+    body: &[u8],
+    binary: bool,
+    modified: DateTime<Local>,
+    config: &Config,
+) -> String {
+    if binary {
+        return "[binary file changed]".to_string();
+    }
+
+    let diff = unified_diff(path, current, body, modified, diff_timestamp, config);
+    let max_lines = config.max_diff_lines.unwrap_or(50);
+    let mut lines: Vec<_> = diff.lines().collect();
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        lines.push("[...truncated]");
+    }
+
+    lines.join("\n")
+}
+
+/// Renders the complete diff for `diff_log_path`, with a header naming the file.
+fn render_diff_log(
+    path: &str,
+    current: &[u8],
+    body: &[u8],
+    binary: bool,
+    modified: DateTime<Local>,
+    config: &Config,
+) -> String {
+    if binary {
+        let timestamp = diff_log_timestamp(Local::now());
+        return format!("--- {path}\n+++ {path}\t{timestamp}\n[binary file changed]\n");
+    }
+
+    unified_diff(path, current, body, modified, diff_log_timestamp, config)
+}
+
+/// Appends a diff to the end of the file, logging failures.
+async fn append_diff_log(path: &str, diff: &str) {
+    let mut file = match fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+    {
+        Ok(file) => file,
+        Err(err) => {
+            log::warn!("Failed to open diff log {:?}: {}", path, err);
+            return;
+        }
+    };
+
+    if let Err(err) = file.write_all(diff.as_bytes()).await {
+        log::warn!("Failed to write diff log {:?}: {}", path, err);
+    }
+}
+
+/// Creates the directory of `path` if its directories couldn't be created at startup because
+/// it has placeholders.
+async fn create_parent_directories(path: &str, entry: &FileEntry, config: &Config) -> Result<()> {
+    if entry.paths.iter().any(|path| path.contains("{{"))
+        && entry
+            .create_directories
+            .unwrap_or(config.create_directories)
+        && let Some(parent) = Path::new(path).parent()
+    {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create directories for {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Writes the downloaded file, or extracts the archive, to one of the entry's paths. Streamed
+/// bodies are copied from their temporary file instead, or moved there for the `last` path.
+async fn write_output(
+    path: &str,
+    body: &[u8],
+    streamed: Option<&StreamedBody>,
+    last: bool,
+    entry: &FileEntry,
+    config: &Config,
+    write_locks: &WriteLocks,
+) -> Result<()> {
+    let _lock = write_locks
+        .lock(Path::new(path), config.file_lock.unwrap_or(false))
+        .await
+        .with_context(|| format!("Failed to lock {path:?}"))?;
+
+    create_parent_directories(path, entry, config).await?;
+
+    if let Some(extract) = &entry.extract {
+        let tmp_path = temp_path(Path::new(path), None);
+        return tokio::task::block_in_place(|| {
+            extract::extract(body, &tmp_path, Path::new(path), extract)
+        })
+        .with_context(|| format!("Failed to extract archive to {path:?}"));
+    }
+
+    if entry.backup.or(config.backup).unwrap_or(false)
+        && fs::try_exists(path).await.unwrap_or(false)
+    {
+        let backup_path = backup_path(path, config.backup_suffix.as_deref());
+        fs::copy(path, &backup_path)
+            .await
+            .with_context(|| format!("Failed to back up {path:?}"))?;
+        log::debug!("Backed up {} to {}", path, backup_path);
+    }
+    let temp_dir = config.temp_dir.as_deref();
+    // Copied when verifying, since the write may have to be repeated
+    let rename = last && !config.verify_write.unwrap_or(false);
+    for attempt in 1..=WRITE_ATTEMPTS {
+        match streamed.and_then(|streamed| streamed.tmp_path.as_deref()) {
+            Some(tmp_path) if rename => replace_file(tmp_path, Path::new(path), entry.mode).await,
+            Some(tmp_path) => {
+                copy_file(tmp_path, path, config.atomic_writes, temp_dir, entry.mode).await
+            }
+            None => write_file(path, body, config.atomic_writes, temp_dir, entry.mode).await,
+        }
+        .with_context(|| format!("Failed to write file to {path:?}"))?;
+        if !config.verify_write.unwrap_or(false) {
+            return Ok(());
+        }
+
+        let expected =
+            streamed.map_or_else(|| Sha256::digest(body).into(), |streamed| streamed.sha256);
+        let written = hash_file(Path::new(path))
+            .await
+            .with_context(|| format!("Failed to read back {path:?}"))?;
+        if written == Some(expected) {
+            return Ok(());
+        }
+        log::error!(
+            "Contents of {} don't match what was written (attempt {}/{})",
+            path,
+            attempt,
+            WRITE_ATTEMPTS
+        );
+    }
+
+    anyhow::bail!(
+        "Failed to write file to {path:?}: still different after {WRITE_ATTEMPTS} attempts"
+    )
+}
+
+/// Replaces `{{date}}`, `{{time}}`, `{{datetime}}`, `{{year}}`, `{{month}}`, `{{day}}`,
+/// `{{hour}}` and `{{minute}}` with the respective part of `now`.
+fn expand_placeholders(value: &str, now: &DateTime<FixedOffset>) -> String {
+    if !value.contains("{{") {
+        return value.to_string();
+    }
+
+    [
+        ("date", "%Y-%m-%d"),
+        ("time", "%H-%M-%S"),
+        ("datetime", "%Y-%m-%dT%H-%M-%S"),
+        ("year", "%Y"),
+        ("month", "%m"),
+        ("day", "%d"),
+        ("hour", "%H"),
+        ("minute", "%M"),
+    ]
+    .iter()
+    .fold(value.to_string(), |value, (name, format)| {
+        value.replace(&format!("{{{{{name}}}}}"), &now.format(format).to_string())
+    })
+}
+
+/// Path at which the body of a `resumable` entry is stored while it is received.
+fn part_path(path: &str) -> String {
+    format!("{path}.part")
+}
+
+/// Keeps the validator of the response a part file belongs to, to be sent as `If-Range`.
+fn if_range_path(part_path: &str) -> String {
+    format!("{part_path}.if-range")
+}
+
+/// A strong `ETag`, or otherwise `Last-Modified`, since `If-Range` doesn't allow weak ones.
+fn if_range_validator(resp: &Response) -> Option<&str> {
+    let header = |name| resp.headers().get(name)?.to_str().ok();
+    header(header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(header::LAST_MODIFIED))
+}
+
+/// Removes a part file together with its validator.
+async fn remove_part(part_path: &str) {
+    let _ = fs::remove_file(part_path).await;
+    let _ = fs::remove_file(if_range_path(part_path)).await;
+}
+
+/// Streams the body into the part file, appending to it for `206 Partial Content` responses,
+/// while hashing the complete contents. The finished part file is moved into place like the
+/// temporary file of `streaming` entries.
+async fn receive_part(
+    resp: &mut Response,
+    part_path: &str,
+    mut throttle: Option<Throttle>,
+) -> Result<StreamedBody> {
+    let partial = resp.status() == StatusCode::PARTIAL_CONTENT;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(partial)
+        .truncate(!partial)
+        .open(part_path)
+        .await?;
+
+    if !partial {
+        // Without a validator, the part file is started over instead of being resumed
+        match if_range_validator(resp) {
+            Some(validator) => fs::write(if_range_path(part_path), validator).await?,
+            None => {
+                let _ = fs::remove_file(if_range_path(part_path)).await;
+            }
+        }
+    }
+    let mut sha256 = Sha256::new();
+    let mut md5 = md5::Context::new();
+    let mut len = 0;
+    if partial {
+        let offset = file.metadata().await?.len();
+        let start = resp
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.strip_prefix("bytes "))
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(start, _)| start.parse::<u64>().ok());
+        if start != Some(offset) {
+            drop(file);
+            remove_part(part_path).await;
+            anyhow::bail!("Partial response doesn't continue at byte {offset}");
+        }
+
+        // Hashed in chunks, like the rest of the body
+        let mut part = fs::File::open(part_path).await?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match part.read(&mut buf).await? {
+                0 => break,
+                read => {
+                    sha256.update(&buf[..read]);
+                    len += read;
+                }
+            }
+        }
+    }
+
+    while let Some(chunk) = resp.chunk().await.map_err(reqwest::Error::without_url)? {
+        file.write_all(&chunk).await?;
+        sha256.update(&chunk);
+        md5.consume(&chunk);
+        len += chunk.len();
+        if let Some(throttle) = &mut throttle {
+            throttle.consume(chunk.len()).await;
+        }
+    }
+    file.flush().await?;
+    drop(file);
+    let _ = fs::remove_file(if_range_path(part_path)).await;
+
+    Ok(StreamedBody {
+        tmp_path: Some(part_path.into()),
+        len,
+        sha256: sha256.finalize().into(),
+        md5: md5.finalize().0,
+    })
+}
+
+/// Receives the body chunk by chunk, so that the throughput can be limited.
+async fn receive_chunks(
+    resp: &mut Response,
+    mut throttle: Option<Throttle>,
+) -> reqwest::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if let Some(throttle) = &mut throttle {
+            throttle.consume(chunk.len()).await;
+        }
+    }
+
+    Ok(body)
+}
+
+/// Receives the body of a successful response, into the part file of `resumable` entries or to
+/// `tmp_path` for `streaming` ones. Errors of the connection can be told from others by
+/// downcasting them to `reqwest::Error`.
+async fn receive_body(
+    resp: &mut Response,
+    entry: &FileEntry,
+    part_path: Option<&str>,
+    tmp_path: Option<PathBuf>,
+    throttle: Option<Throttle>,
+) -> Result<(Vec<u8>, Option<StreamedBody>)> {
+    let streaming = entry.streaming.unwrap_or(false);
+    match part_path {
+        Some(part_path) => {
+            // The part file is kept if this fails, to be continued by the next attempt
+            let streamed = receive_part(resp, part_path, throttle)
+                .await
+                .with_context(|| format!("Failed to receive {part_path:?}"))?;
+            // Archives and formats are checked in memory, removing the part file right away
+            if entry.extract.is_some() || entry.validate_format.is_some() {
+                let body = fs::read(part_path)
+                    .await
+                    .with_context(|| format!("Failed to read {part_path:?}"))?;
+                return Ok((body, None));
+            }
+            Ok((Vec::new(), Some(streamed)))
+        }
+        // The temporary file is removed by `StreamedBody` if this fails
+        None if streaming => {
+            let streamed = receive_stream(resp, tmp_path, throttle)
+                .await
+                .context("Failed to receive to a temporary file")?;
+            Ok((Vec::new(), Some(streamed)))
+        }
+        None => Ok((
+            receive_chunks(resp, throttle)
+                .await
+                .map_err(reqwest::Error::without_url)?,
+            None,
+        )),
+    }
+}
+
+/// Body of a `streaming` or `resumable` entry, whose temporary or part file is removed when
+/// dropped unless it was moved into place.
+struct StreamedBody {
+    /// `None` in dry runs, which only hash the body.
+    tmp_path: Option<PathBuf>,
+    len: usize,
+    sha256: [u8; 32],
+    /// Of the bytes of this response only, which is what `Content-MD5` covers for resumed ones.
+    md5: [u8; 16],
+}
+
+impl Drop for StreamedBody {
+    fn drop(&mut self) {
+        if let Some(tmp_path) = &self.tmp_path {
+            let _ = std::fs::remove_file(tmp_path);
+        }
+    }
+}
+
+/// Streams the body to `tmp_path` while hashing it, without keeping it in memory.
+async fn receive_stream(
+    resp: &mut Response,
+    tmp_path: Option<PathBuf>,
+    mut throttle: Option<Throttle>,
+) -> Result<StreamedBody> {
+    let mut file = match &tmp_path {
+        Some(tmp_path) => Some(fs::File::create(tmp_path).await?),
+        None => None,
+    };
+    // Created right away so that the file is removed if the download fails
+    let mut streamed = StreamedBody {
+        tmp_path,
+        len: 0,
+        sha256: [0; 32],
+        md5: [0; 16],
+    };
+
+    let mut sha256 = Sha256::new();
+    let mut md5 = md5::Context::new();
+    while let Some(chunk) = resp.chunk().await.map_err(reqwest::Error::without_url)? {
+        sha256.update(&chunk);
+        md5.consume(&chunk);
+        streamed.len += chunk.len();
+        if let Some(file) = &mut file {
+            file.write_all(&chunk).await?;
+        }
+        if let Some(throttle) = &mut throttle {
+            throttle.consume(chunk.len()).await;
+        }
+    }
+    if let Some(file) = &mut file {
+        file.flush().await?;
+    }
+
+    streamed.sha256 = sha256.finalize().into();
+    streamed.md5 = md5.finalize().0;
+    Ok(streamed)
+}
+
+fn normalize_line_endings(contents: &[u8]) -> Vec<u8> {
+    String::from_utf8_lossy(contents)
+        .replace("\r\n", "\n")
+        .into_bytes()
+}
+
+fn detect_change(
+    current: &[u8],
+    body: &[u8],
+    binary: bool,
+    algorithm: DiffAlgorithm,
+    detection: ChangeDetection,
+) -> Change {
+    match detection {
+        ChangeDetection::Diff => {}
+        ChangeDetection::Hash if Sha256::digest(current) == Sha256::digest(body) => {
+            return Change::None;
+        }
+        ChangeDetection::Hash | ChangeDetection::Always => return Change::Unknown,
+    }
+
+    if binary {
+        return if current == body {
+            Change::None
+        } else {
+            Change::Binary
+        };
+    }
+
+    let current = String::from_utf8_lossy(current);
+    let body = String::from_utf8_lossy(body);
+    let input = InternedInput::new(current.as_ref(), body.as_ref());
+    let diff = Diff::compute(algorithm.into(), &input);
+
+    match (diff.count_additions(), diff.count_removals()) {
+        (0, 0) => Change::None,
+        (additions, removals) => Change::Text {
+            additions,
+            removals,
+        },
+    }
+}
+
+fn temp_path(path: &Path, temp_dir: Option<&str>) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".knot-tmp-{:016x}", rand::random::<u64>()));
+    match temp_dir {
+        Some(temp_dir) => Path::new(temp_dir).join(file_name),
+        None => path.with_file_name(file_name),
+    }
+}
+
+async fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        // `MoveFileEx` refuses to replace a file that is open without `FILE_SHARE_DELETE`
+        // (e.g. by a reader), so retry once after removing the destination.
+        match fs::rename(from, to).await {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                fs::remove_file(to).await?;
+                fs::rename(from, to).await
+            }
+            res => res,
+        }
+    }
+
+    #[cfg(not(windows))]
+    {
+        fs::rename(from, to).await
+    }
+}
+
+/// Applies `mode` to `path`, or otherwise the permissions of `original` if it exists.
+async fn set_permissions(path: &Path, original: &Path, mode: Option<u32>) -> io::Result<()> {
+    let permissions = match mode {
+        #[cfg(unix)]
+        Some(mode) => {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::Permissions::from_mode(mode)
+        }
+        #[cfg(not(unix))]
+        Some(_) => {
+            log::warn!("Ignoring `mode` for {path:?}, file modes are only supported on Unix");
+            return Ok(());
+        }
+        None => match fs::metadata(original).await {
+            Ok(metadata) => metadata.permissions(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        },
+    };
+
+    fs::set_permissions(path, permissions).await
+}
+
+/// Writes `contents` to `path`. Atomic writes go to a temporary file in `temp_dir`, or next to
+/// `path` by default, which is then renamed into place, or copied if it is on another filesystem.
+async fn write_file(
+    path: &str,
+    contents: &[u8],
+    atomic: bool,
+    temp_dir: Option<&str>,
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let path = Path::new(path);
+
+    if !atomic {
+        fs::write(path, contents).await?;
+        return set_permissions(path, path, mode).await;
+    }
+
+    let tmp_path = temp_path(path, temp_dir);
+    let res = match fs::write(&tmp_path, contents).await {
+        Ok(()) => replace_file(&tmp_path, path, mode).await,
+        Err(err) => Err(err),
+    };
+    if res.is_err() || fs::try_exists(&tmp_path).await.unwrap_or(false) {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+
+    res
+}
+
+/// Like `write_file`, but with the contents of the file at `source`.
+async fn copy_file(
+    source: &Path,
+    path: &str,
+    atomic: bool,
+    temp_dir: Option<&str>,
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let path = Path::new(path);
+
+    if !atomic {
+        fs::copy(source, path).await?;
+        return set_permissions(path, path, mode).await;
+    }
+
+    let tmp_path = temp_path(path, temp_dir);
+    let res = match fs::copy(source, &tmp_path).await {
+        Ok(_) => replace_file(&tmp_path, path, mode).await,
+        Err(err) => Err(err),
+    };
+    if res.is_err() || fs::try_exists(&tmp_path).await.unwrap_or(false) {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+
+    res
+}
+
+/// Moves the temporary file of an atomic write into place, or copies it if it is on another
+/// filesystem, leaving it for the caller to remove.
+async fn replace_file(tmp_path: &Path, path: &Path, mode: Option<u32>) -> io::Result<()> {
+    set_permissions(tmp_path, path, mode).await?;
+    match rename(tmp_path, path).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!("Copying {:?} to {:?} across filesystems", tmp_path, path);
+            fs::copy(tmp_path, path).await.map(|_| ())
+        }
+        res => res,
+    }
+}
+
+/// SHA-256 of the file at `path`, read in chunks, or `None` if it doesn't exist.
+async fn hash_file(path: &Path) -> io::Result<Option<[u8; 32]>> {
+    let mut file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf).await? {
+            0 => return Ok(Some(hasher.finalize().into())),
+            len => hasher.update(&buf[..len]),
+        }
+    }
+}
+
+fn parse_state(contents: &[u8]) -> Result<Cache> {
+    let state: serde_json::Value = serde_json::from_slice(contents)?;
+    if state["version"].as_u64() != Some(STATE_VERSION) {
+        anyhow::bail!("Unsupported version {}", state["version"]);
+    }
+
+    Ok(serde_json::from_value(state)?)
+}
+
+/// Loads the validators saved by a previous run, starting with an empty cache if there are none.
+async fn load_state(path: &str) -> Cache {
+    let contents = match fs::read(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Cache::default(),
+        Err(err) => {
+            log::warn!("Failed to read state file {:?}: {}", path, err);
+            return Cache::default();
+        }
+    };
+
+    match parse_state(&contents) {
+        Ok(mut cache) => {
+            log::debug!(
+                "Loaded {} validators from {:?}",
+                cache.etags.len() + cache.last_modified.len(),
+                path
+            );
+            let urls: HashSet<_> = cache
+                .etags
+                .keys()
+                .chain(cache.last_modified.keys())
+                .cloned()
+                .collect();
+            for url in urls {
+                cache.recent.put(url, ());
+            }
+            cache
+        }
+        Err(err) => {
+            log::warn!("Ignoring state file {:?}: {:#}", path, err);
+            Cache::default()
+        }
+    }
+}
+
+async fn save_state(path: &str, cache: &Mutex<Cache>) {
+    let contents = {
+        let cache = cache.lock().unwrap();
+        serde_json::json!({
+            "version": STATE_VERSION,
+            "etags": cache.etags,
+            "last_modified": cache.last_modified,
+        })
+        .to_string()
+    };
+
+    if let Err(err) = write_file(path, contents.as_bytes(), true, None, None).await {
+        log::error!("Failed to write state file {:?}: {}", path, err);
+    }
+}
+
+fn backup_path(path: &str, suffix: Option<&str>) -> String {
+    let suffix = Local::now().format(suffix.unwrap_or(".bak"));
+    format!("{path}{suffix}")
+}
+
+/// Returns the encoding from the `charset` parameter of the `Content-Type` header.
+fn response_charset(resp: &Response) -> Option<&'static Encoding> {
+    let content_type = resp.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let charset = content_type.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })?;
+
+    Encoding::for_label(charset.as_bytes())
+}
+
+/// Checks that the body parses in the given format, e.g. to reject error pages.
+fn validate_format(format: ValidateFormat, body: &[u8]) -> Result<()> {
+    let body = std::str::from_utf8(body).context("Invalid UTF-8")?;
+
+    match format {
+        ValidateFormat::Json => {
+            serde_json::from_str::<serde_json::Value>(body).context("Invalid JSON")?;
+        }
+        ValidateFormat::Yaml => {
+            serde_yaml::from_str::<serde_yaml::Value>(body).context("Invalid YAML")?;
+        }
+        #[cfg(feature = "toml-config")]
+        ValidateFormat::Toml => {
+            toml::from_str::<toml::Table>(body).context("Invalid TOML")?;
+        }
+        #[cfg(not(feature = "toml-config"))]
+        ValidateFormat::Toml => {
+            anyhow::bail!("TOML support is disabled (enable the `toml-config` feature)")
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a symlink at `link` pointing to `target`, replacing an existing symlink but never a
+/// regular file.
+async fn update_symlink(link: &str, target: &str) -> Result<()> {
+    let target = std::path::absolute(target)?;
+
+    match fs::symlink_metadata(link).await {
+        Ok(metadata) if !metadata.file_type().is_symlink() => {
+            anyhow::bail!("{link:?} already exists and is not a symlink")
+        }
+        Ok(_) => {
+            if fs::read_link(link).await? == target {
+                return Ok(());
+            }
+            fs::remove_file(link).await?;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    #[cfg(unix)]
+    fs::symlink(&target, link).await?;
+    #[cfg(windows)]
+    fs::symlink_file(&target, link).await?;
+    log::debug!("Linked {} to {:?}", link, target);
+
+    Ok(())
+}
+
+/// Returns the time until the next match of a standard five-field cron expression.
+fn until_next_run(expression: &str) -> Result<Duration> {
+    if expression.split_whitespace().count() != 5 {
+        anyhow::bail!("Expected five fields in cron expression {expression:?}");
+    }
+    // The `cron` crate expects an additional seconds field
+    let schedule = cron::Schedule::from_str(&format!("0 {expression}"))
+        .with_context(|| format!("Invalid cron expression {expression:?}"))?;
+    let next = schedule
+        .upcoming(Local)
+        .next()
+        .with_context(|| format!("Cron expression {expression:?} never matches"))?;
+
+    Ok((next - Local::now()).to_std().unwrap_or_default())
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Delay requested by a `Retry-After` header, in seconds or as an HTTP date, capped at
+/// `retry_max_delay`.
+fn retry_after(resp: &Response, config: &Config) -> Option<Duration> {
+    let value = resp
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    let delay = match value.parse() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    };
+    Some(delay.min(config.retry_max_delay.unwrap_or(DEFAULT_RETRY_MAX_DELAY)))
+}
+
+fn retry_on_status(config: &Config) -> &[u16] {
+    config
+        .retry_on_status
+        .as_deref()
+        .unwrap_or(&DEFAULT_RETRY_ON_STATUS)
+}
+
+/// Exponential backoff before retry number `attempt + 1`, including the `retry_jitter`.
+fn backoff(attempt: u32, config: &Config) -> Duration {
+    let backoff = config
+        .retry_base_delay
+        .saturating_mul(2u32.saturating_pow(attempt));
+    let backoff = backoff.min(config.retry_max_delay.unwrap_or(DEFAULT_RETRY_MAX_DELAY));
+    let jitter = config.retry_jitter.unwrap_or(0.0);
+    backoff.mul_f64(1.0 + rand::random::<f64>() * jitter)
+}
+
+/// Returns how long to wait before retrying the request, or `None` if the failure is permanent.
+fn retry_delay(res: &reqwest::Result<Response>, attempt: u32, config: &Config) -> Option<Duration> {
+    let backoff = backoff(attempt, config);
+
+    match res {
+        Ok(resp) if !retry_on_status(config).contains(&resp.status().as_u16()) => None,
+        Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => {
+            Some(retry_after(resp, config).unwrap_or(backoff))
+        }
+        Ok(_) => Some(backoff),
+        Err(err) if err.is_connect() || err.is_timeout() => Some(backoff),
+        Err(_) => None,
+    }
+}
+
+/// Logs which of the timeouts expired, for errors sending the request or receiving its body.
+fn warn_timeout(err: &reqwest::Error, log_url: &str, config: &Config, timeout: Option<Duration>) {
+    match (err.is_connect(), config.connect_timeout, timeout) {
+        (true, Some(timeout), _) => {
+            log::warn!("Timed out connecting to {} after {:?}", log_url, timeout)
+        }
+        (_, _, Some(timeout)) => {
+            log::warn!("Timed out downloading {} after {:?}", log_url, timeout)
+        }
+        (_, _, None) if let Some(timeout) = config.read_timeout => {
+            log::warn!("Received nothing from {} for {:?}", log_url, timeout)
+        }
+        _ => log::warn!("Timed out downloading {}", log_url),
+    }
+}
+
+/// Describes why a download failed, for logging the retries and mirrors tried after it.
+fn failure_reason(
+    res: &reqwest::Result<Response>,
+    received: Option<&Result<(Vec<u8>, Option<StreamedBody>)>>,
+) -> String {
+    match (res, received) {
+        (_, Some(Err(err))) => format!("{err:#}"),
+        (Ok(resp), _) => resp.status().to_string(),
+        (Err(err), _) => err.to_string(),
+    }
+}
+
+fn build_proxy(config: &ProxyConfig) -> Result<Proxy> {
+    let (scheme, address) = match config.url.split_once("://") {
+        Some((scheme, address)) => (Some(scheme), address),
+        None => (None, config.url.as_str()),
+    };
+    let protocol = match (config.protocol, scheme) {
+        (Some(protocol), _) => protocol,
+        (None, Some(scheme)) => ProxyProtocol::from_scheme(scheme).with_context(|| {
+            format!("Unsupported proxy scheme {scheme:?} (expected http, https, socks5 or socks5h)")
+        })?,
+        (None, None) => ProxyProtocol::Http,
+    };
+    let url = format!("{}://{}", protocol.scheme(), address);
+
+    let mut proxy =
+        Proxy::all(&url).with_context(|| format!("Invalid proxy URL {:?}", redact_url(&url)))?;
+    if let Some(username) = &config.username {
+        let password = config.password.as_deref().map(resolve_secret).transpose()?;
+        proxy = proxy.basic_auth(username, password.as_deref().unwrap_or_default());
+    }
+    if let Some(no_proxy) = &config.no_proxy {
+        proxy = proxy.no_proxy(NoProxy::from_string(&no_proxy.join(",")));
+    }
+
+    Ok(proxy)
+}
+
+fn load_identity(config: &TlsConfig) -> Result<Identity> {
+    let mut pem = match (&config.client_cert_pem, &config.client_cert_path) {
+        (Some(pem), None) => pem.clone().into_bytes(),
+        (None, Some(path)) => std::fs::read(path)
+            .with_context(|| format!("Failed to read client certificate from {path:?}"))?,
+        _ => anyhow::bail!("Exactly one of `client_cert_path` and `client_cert_pem` must be set"),
+    };
+    if let Some(path) = &config.client_key_path {
+        let key = std::fs::read(path)
+            .with_context(|| format!("Failed to read client key from {path:?}"))?;
+        pem.push(b'\n');
+        pem.extend(key);
+    }
+
+    Identity::from_pem(&pem).context("Invalid client certificate or key")
+}
+
+/// Loads all certificates from a PEM bundle, or from every file in a directory such as
+/// `/etc/ssl/certs` (including OpenSSL's `<hash>.0` links).
+fn load_ca_certificates(path: &str) -> Result<Vec<Certificate>> {
+    let path = Path::new(path);
+    let files = if path.is_dir() {
+        let mut files = std::fs::read_dir(path)
+            .with_context(|| format!("Failed to read CA directory {path:?}"))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()
+            .with_context(|| format!("Failed to read CA directory {path:?}"))?;
+        files.retain(|file| file.is_file());
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut certificates = Vec::new();
+    for file in files {
+        let pem = std::fs::read(&file)
+            .with_context(|| format!("Failed to read CA certificates from {file:?}"))?;
+        let bundle = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA certificates in {file:?}"))?;
+        if bundle.is_empty() {
+            anyhow::bail!("No CA certificates found in {file:?}");
+        }
+        certificates.extend(bundle);
+    }
+
+    Ok(certificates)
+}
+
+/// Identifies the connection settings of an entry, so that entries with the same ones (e.g. the
+/// same client certificate) share a client.
+fn client_key(entry: &FileEntry) -> String {
+    format!(
+        "{:?}",
+        (
+            &entry.proxy,
+            &entry.tls,
+            &entry.tls_ca_bundle,
+            entry.tls_insecure,
+            entry.max_redirects,
+            entry.decompress,
+            entry.http_version,
+            &entry.unix_socket,
+        )
+    )
+}
+
+/// Entries with their own connection settings can't share the global client.
+fn needs_own_client(entry: &FileEntry) -> bool {
+    entry.proxy.is_some()
+        || entry.tls.is_some()
+        || entry.tls_ca_bundle.is_some()
+        || entry.tls_insecure.is_some()
+        || entry.max_redirects.is_some()
+        || entry.decompress == Some(false)
+        || entry.http_version.is_some()
+        || entry.unix_socket.is_some()
+}
+
+/// Follows up to `max` redirects, then stops and returns the redirect response itself.
+fn redirect_policy(max: u32) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() <= max as usize {
+            return attempt.follow();
+        }
+
+        let chain: Vec<_> = attempt
+            .previous()
+            .iter()
+            .chain([attempt.url()])
+            .map(|url| redact_url(url.as_str()))
+            .collect();
+        log::warn!(
+            "Stopped following redirects after {}: {}",
+            max,
+            chain.join(" -> ")
+        );
+        attempt.stop()
+    })
+}
+
+/// Builds the HTTP client for `entry`, or the one shared by all other entries for `None`.
+/// Clients transparently decompress `gzip` and `deflate` responses unless `decompress` is `false`.
+///
+/// All clients share the cookie jar, which outlives them since they are rebuilt on reloads.
+fn build_client(
+    config: &Config,
+    entry: Option<&FileEntry>,
+    cookies: &Arc<CookieStoreMutex>,
+) -> Result<Client> {
+    let user_agent = config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+    let mut builder = Client::builder().user_agent(user_agent);
+    if entry.and_then(|entry| entry.decompress) == Some(false) {
+        builder = builder.no_gzip().no_deflate();
+    }
+    match entry.and_then(|entry| entry.http_version) {
+        Some(HttpVersion::Http11) => builder = builder.http1_only(),
+        Some(HttpVersion::Http2) => builder = builder.http2_prior_knowledge(),
+        None => {}
+    }
+    let proxy = entry.and_then(|entry| entry.proxy.as_ref());
+    if let Some(proxy) = proxy.or(config.proxy.as_ref()) {
+        builder = builder.proxy(build_proxy(proxy)?);
+    }
+    #[cfg(unix)]
+    if let Some(path) = entry.and_then(|entry| entry.unix_socket.as_deref()) {
+        builder = builder.unix_socket(path);
+    }
+    let tls = entry.and_then(|entry| entry.tls.as_ref());
+    if let Some(tls) = tls.or(config.tls.as_ref()) {
+        builder = builder.identity(load_identity(tls)?);
+    }
+    let max_redirects = entry.and_then(|entry| entry.max_redirects);
+    let max_redirects = max_redirects.or(config.max_redirects).unwrap_or(10);
+    builder = builder.redirect(redirect_policy(max_redirects));
+    let ca_bundle = entry.and_then(|entry| entry.tls_ca_bundle.as_ref());
+    for path in config.tls_ca_bundle.iter().chain(ca_bundle) {
+        for certificate in load_ca_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    let tls_insecure = entry.and_then(|entry| entry.tls_insecure);
+    if tls_insecure.or(config.tls_insecure).unwrap_or(false) {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(timeout) = config.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = config.read_timeout {
+        builder = builder.read_timeout(timeout);
+    }
+    if config
+        .cookie_store
+        .unwrap_or(config.cookie_store_path.is_some())
+    {
+        builder = builder.cookie_provider(cookies.clone());
+    }
+
+    builder.build().context("Failed to build HTTP client")
+}
+
+/// HTTP clients of a download. Requests to other hosts, like webhooks and OAuth2 token URLs, use
+/// the global client, since the entry's own one may connect elsewhere, e.g. to its `unix_socket`.
+struct Clients {
+    entry: Client,
+    global: Client,
+}
+
+async fn download_file(
+    clients: &Clients,
+    entry: &FileEntry,
+    config: &Config,
+    args: Args,
+    cache: &Mutex<Cache>,
+    rate_limiter: Option<&RateLimiter>,
+    presigner: Option<&Presigner>,
+) -> Result<(Outcome, usize)> {
+    let client = &clients.entry;
+    // Expanded for every download so that dated URLs move on with time
+    let now = match config.template_timezone {
+        TemplateTimezone::Local => Local::now().fixed_offset(),
+        TemplateTimezone::Utc => Utc::now().fixed_offset(),
+    };
+    let urls: Vec<_> = entry
+        .urls
+        .iter()
+        .map(|url| expand_placeholders(url, &now))
+        .collect();
+    let paths: Vec<_> = entry
+        .paths
+        .iter()
+        .map(|path| expand_placeholders(path, &now))
+        .collect();
+    let path = paths[0].as_str();
+    let log_url = redact_url(&urls[0]);
+
+    // Run first since it may provide the secrets resolved below
+    if let Some(cmd) = &entry.before_download
+        && !args.dry_run
+    {
+        let env = [
+            ("KNOT_URL", log_url.clone()),
+            ("KNOT_PATH", path.to_string()),
+        ];
+        let succeeded = match hooks::run(cmd, &env, config.on_change_timeout).await {
+            Ok(succeeded) => succeeded,
+            Err(err) => {
+                log::error!("{:#}", err);
+                false
+            }
+        };
+        if !succeeded {
+            log::error!("Skipped {} as `before_download` failed", log_url);
+            return Ok((Outcome::Skipped, 0));
+        }
+    }
+    let streaming = entry.streaming.unwrap_or(false);
+    let binary = entry.binary.unwrap_or(false) || entry.extract.is_some() || streaming;
+    let timeout = entry.timeout.or(config.request_timeout);
+    let max_retries = entry.max_retries.or(config.max_retries).unwrap_or(0);
+    let method = entry.method.clone().unwrap_or(Method::GET);
+    // No partial files are kept in dry runs, which don't write anything
+    let part_path =
+        (binary && entry.resumable.unwrap_or(false) && !args.dry_run).then(|| part_path(path));
+
+    let password = match entry
+        .auth
+        .as_ref()
+        .map(|auth| resolve_secret(&auth.password))
+    {
+        Some(Ok(password)) => Some(password),
+        Some(Err(err)) => {
+            log::error!("Failed to download {}: {:#}", log_url, err);
+            return Ok((Outcome::Failed, 0));
+        }
+        None => None,
+    };
+
+    let api_key = match entry
+        .api_key
+        .as_ref()
+        .map(|api_key| resolve_secret(&api_key.key))
+    {
+        Some(Ok(key)) => Some(key),
+        Some(Err(err)) => {
+            log::error!("Failed to download {}: {:#}", log_url, err);
+            return Ok((Outcome::Failed, 0));
+        }
+        None => None,
+    };
+
+    let bearer_token = match (&entry.bearer_token, &entry.bearer_token_env, &entry.oauth2) {
+        (Some(token), _, _) => Some(token.clone()),
+        (None, Some(name), _) => match env::var(name) {
+            Ok(token) => Some(token),
+            Err(err) => {
+                log::error!("Failed to download {}: {} ({})", log_url, err, name);
+                return Ok((Outcome::Failed, 0));
+            }
+        },
+        (None, None, Some(oauth2)) => match oauth2.token(&clients.global).await {
+            Ok(token) => Some(token),
+            Err(err) => {
+                log::error!("Failed to download {}: {:#}", log_url, err);
+                return Ok((Outcome::Failed, 0));
+            }
+        },
+        (None, None, None) => None,
+    };
+
+    let detection = entry.change_detection.unwrap_or_default();
+    // Hashed before the download since the body isn't kept for comparing it afterwards
+    let current_sha256 = match detection {
+        _ if !streaming && part_path.is_none() => None,
+        ChangeDetection::Always => None,
+        _ => hash_file(Path::new(path)).await.ok().flatten(),
+    };
+    let tmp_path = if streaming && !args.dry_run {
+        if let Err(err) = create_parent_directories(path, entry, config).await {
+            log::error!("{:#}", err);
+            return Ok((Outcome::Failed, 0));
+        }
+        Some(temp_path(Path::new(path), config.temp_dir.as_deref()))
+    } else {
+        None
+    };
+
+    let started = Instant::now();
+    let mut urls = urls.iter().peekable();
+    // Mirrors are only tried when the previous URL failed, each with its own retries
+    let (url, log_url, res, received, validator, retries) = loop {
+        let url = urls.next().context("No URL to download from")?;
+        let log_url = redact_url(url);
+
+        let mut attempt = 0;
+        let (res, received, validator, retries) = loop {
+            // Presigned right before each attempt so that retries don't use an expired URL
+            let mut req = match presigner.filter(|_| s3::is_s3_url(url)) {
+                Some(presigner) => match presigner.presign(url).await {
+                    Ok(presigned) => client.request(method.clone(), presigned),
+                    Err(err) => {
+                        log::error!("Failed to download {}: {:#}", log_url, err);
+                        return Ok((Outcome::Failed, 0));
+                    }
+                },
+                None => client.request(method.clone(), url),
+            };
+            if let Some(body) = &entry.body {
+                req = req.body(body.clone());
+            }
+            if let Some(content_type) = &entry.content_type {
+                req = req.header(header::CONTENT_TYPE, content_type);
+            }
+            if let Some(part_path) = &part_path {
+                // Compressed responses can't be continued at a byte offset of the decoded body
+                req = req.header(header::ACCEPT_ENCODING, "identity");
+                // Servers send the whole file instead if it changed since the part was received
+                let len = fs::metadata(part_path)
+                    .await
+                    .map_or(0, |metadata| metadata.len());
+                if len > 0
+                    && let Ok(validator) = fs::read_to_string(if_range_path(part_path)).await
+                {
+                    log::debug!("Resuming download of {} at byte {}", log_url, len);
+                    req = req
+                        .header(header::RANGE, format!("bytes={len}-"))
+                        .header(header::IF_RANGE, validator);
+                }
+            }
+            // The global timeout is already configured on the client
+            if let Some(timeout) = entry.timeout {
+                req = req.timeout(timeout);
+            }
+            if let Some(user_agent) = &entry.user_agent {
+                req = req.header(header::USER_AGENT, user_agent);
+            }
+            let expired = {
+                let cache = cache.lock().unwrap();
+                // Validators without a recorded download, e.g. from the `state_file`, count as expired
+                let expired = entry.max_cache_age.is_some_and(|max_age| {
+                    cache
+                        .downloaded
+                        .get(url)
+                        .is_none_or(|downloaded| downloaded.elapsed() >= max_age)
+                });
+                let cached = cache.etags.contains_key(url) || cache.last_modified.contains_key(url);
+                if expired && cached && !args.force {
+                    log::debug!(
+                        "Downloading {} in full, cached validators are older than {:?}",
+                        log_url,
+                        entry.max_cache_age.unwrap_or_default(),
+                    );
+                }
+                expired
+            };
+            let validator = if args.force || expired {
+                None
+            } else {
+                let mut cache = cache.lock().unwrap();
+                if cache.etags.contains_key(url) || cache.last_modified.contains_key(url) {
+                    cache.touch(url, config.etag_cache_size);
+                }
+                let etag = cache.etags.get(url);
+                let last_modified = cache.last_modified.get(url);
+
+                if let Some(etag) = etag {
+                    req = req.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = last_modified {
+                    req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+                }
+
+                // Both are sent for caches along the way, although servers that support
+                // `If-None-Match` ignore `If-Modified-Since`
+                match (etag, last_modified) {
+                    (Some(_), Some(_)) => Some("ETag and Last-Modified"),
+                    (Some(_), None) => Some("ETag"),
+                    (None, Some(_)) => Some("Last-Modified"),
+                    (None, None) => None,
+                }
+            };
+            if let Some(headers) = &entry.headers {
+                log::debug!(
+                    "Sending headers to {}: {}",
+                    log_url,
+                    format_headers(headers)
+                );
+                for (name, value) in headers {
+                    req = req.header(name, value);
+                }
+            }
+            if let (Some(auth), Some(password)) = (&entry.auth, &password) {
+                req = req.basic_auth(&auth.username, Some(password));
+            }
+            if let Some(token) = &bearer_token {
+                req = req.bearer_auth(token);
+            }
+            // Added to the request rather than the URL, which is logged
+            if let (Some(config), Some(key)) = (&entry.api_key, &api_key) {
+                match (&config.header, &config.query_param) {
+                    (Some(header), _) => req = req.header(header, key),
+                    (None, Some(name)) => req = req.query(&[(name, key)]),
+                    (None, None) => {}
+                }
+            }
+
+            if let Some(rate_limiter) = rate_limiter
+                && let Some(delay) = rate_limiter.acquire().await
+            {
+                log::debug!("Delayed request to {} by {:?} (rate limit)", log_url, delay);
+            }
+
+            // The error message would otherwise include the URL with its credentials
+            let mut res = req.send().await.map_err(reqwest::Error::without_url);
+            if let Err(err) = &res
+                && err.is_timeout()
+            {
+                warn_timeout(err, &log_url, config, timeout);
+            }
+
+            // Received within the loop so that interrupted downloads are retried as well
+            let received = match &mut res {
+                Ok(resp) if resp.status().is_success() => {
+                    let receiving = Instant::now();
+                    let throttle = entry
+                        .bandwidth_limit_bps
+                        .or(config.bandwidth_limit_bps)
+                        .map(Throttle::new);
+                    let received = receive_body(
+                        resp,
+                        entry,
+                        part_path.as_deref(),
+                        tmp_path.clone(),
+                        throttle,
+                    )
+                    .await;
+                    if let Ok((body, streamed)) = &received {
+                        let len = streamed
+                            .as_ref()
+                            .map_or(body.len(), |streamed| streamed.len);
+                        let elapsed = receiving.elapsed();
+                        log::debug!(
+                            "Received {} from {} in {:?} ({}/s)",
+                            human_bytes::human_bytes(len as f64),
+                            log_url,
+                            elapsed,
+                            human_bytes::human_bytes(len as f64 / elapsed.as_secs_f64().max(1e-3)),
+                        );
+                    }
+                    Some(received)
+                }
+                _ => None,
+            };
+            // Unlike failing to write the body, errors of the connection are retried
+            let delay = match &received {
+                Some(Err(err)) => err.downcast_ref::<reqwest::Error>().map(|err| {
+                    if err.is_timeout() {
+                        warn_timeout(err, &log_url, config, timeout);
+                    }
+                    backoff(attempt, config)
+                }),
+                _ => retry_delay(&res, attempt, config),
+            };
+            match delay {
+                Some(delay) if attempt < max_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Retrying {} in {:?} (attempt {}/{}): {}",
+                        log_url,
+                        delay,
+                        attempt,
+                        max_retries,
+                        failure_reason(&res, received.as_ref()),
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                _ => break (res, received, validator, attempt),
+            }
+        };
+
+        let available = matches!(&received, Some(Ok(_)))
+            || matches!(&res, Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED);
+        match urls.peek() {
+            Some(next) if !available => log::warn!(
+                "Failed to download {}, trying {}: {}",
+                log_url,
+                redact_url(next),
+                failure_reason(&res, received.as_ref()),
+            ),
+            _ => break (url, log_url, res, received, validator, retries),
+        }
+    };
+
+    let status = match &res {
+        Ok(resp) => resp.status().as_str().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    metrics::record_download(&log_url, &status);
+
+    let retried = match retries {
+        0 => String::new(),
+        retries => format!(" after {retries} retries"),
+    };
+    let mut bytes = 0;
+    let outcome = match (res, received) {
+        (Ok(resp), Some(Ok((raw_body, streamed)))) => {
+            let header_value = |name| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|value: &HeaderValue| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            let etag = header_value(header::ETAG);
+            let last_modified = header_value(header::LAST_MODIFIED);
+            let content_md5 = header_value(HeaderName::from_static("content-md5"));
+            let headers = entry
+                .save_headers_to
+                .as_ref()
+                .map(|_| headers_json(resp.headers()));
+
+            let encoding = entry
+                .encoding
+                .as_deref()
+                .and_then(|label| Encoding::for_label(label.as_bytes()));
+            let charset = response_charset(&resp);
+            if let (Some(encoding), Some(charset)) = (encoding, charset)
+                && charset != encoding
+                && charset != encoding_rs::UTF_8
+            {
+                log::warn!(
+                    "{} is served as {} but decoded as {}",
+                    log_url,
+                    charset.name(),
+                    encoding.name(),
+                );
+            }
+
+            let received = streamed
+                .as_ref()
+                .map_or(raw_body.len(), |streamed| streamed.len);
+
+            // Verified before decoding since the header covers the body as sent
+            if let Some(expected) = &content_md5
+                && config.verify_content_md5.unwrap_or(true)
+            {
+                let actual = match &streamed {
+                    Some(streamed) => Some(streamed.md5),
+                    None if resp.status() == StatusCode::PARTIAL_CONTENT => {
+                        log::debug!(
+                            "Not verifying Content-MD5 of {}, which covers only the resumed part",
+                            log_url
+                        );
+                        None
+                    }
+                    None => Some(md5::compute(&raw_body).0),
+                };
+                if let Some(actual) = actual.map(|actual| BASE64_STANDARD.encode(actual))
+                    && actual != expected.trim()
+                {
+                    log::error!(
+                        "Failed to verify {}: Content-MD5 mismatch (expected {}, got {})",
+                        log_url,
+                        expected,
+                        actual,
+                    );
+                    return Ok((Outcome::Failed, received));
+                }
+            }
+
+            // Text is decoded like `Response::text` unless an `encoding` is configured
+            let mut body = match encoding {
+                _ if binary => raw_body,
+                Some(encoding) => encoding.decode(&raw_body).0.into_owned().into_bytes(),
+                None => charset
+                    .unwrap_or(encoding_rs::UTF_8)
+                    .decode(&raw_body)
+                    .0
+                    .into_owned()
+                    .into_bytes(),
+            };
+            bytes = streamed
+                .as_ref()
+                .map_or(body.len(), |streamed| streamed.len);
+            metrics::record_body(&log_url, bytes, started.elapsed());
+
+            if let Some(expected) = &entry.sha256 {
+                let actual = match &streamed {
+                    Some(streamed) => hex(&streamed.sha256),
+                    None => sha256_hex(&body),
+                };
+                if !actual.eq_ignore_ascii_case(expected) {
+                    log::error!(
+                        "Failed to verify {}: SHA-256 mismatch (expected {}, got {})",
+                        log_url,
+                        expected,
+                        actual,
+                    );
+                    return Ok((Outcome::Failed, bytes));
+                }
+            }
+
+            // Checked before updating the cache so that the file is downloaded again next time
+            if let Some(format) = entry.validate_format
+                && let Err(err) = validate_format(format, &body)
+            {
+                log::error!("Failed to validate {}: {:#}", log_url, err);
+                return Ok((Outcome::Failed, body.len()));
+            }
+
+            // Also written before updating the cache so that a failure is retried
+            if let (Some(headers_path), Some(headers)) = (&entry.save_headers_to, headers)
+                && !args.dry_run
+            {
+                let count = headers.as_object().map_or(0, |headers| headers.len());
+                if let Err(err) = write_file(
+                    headers_path,
+                    headers.to_string().as_bytes(),
+                    true,
+                    None,
+                    None,
+                )
+                .await
+                {
+                    log::warn!("Failed to write headers to {:?}: {}", headers_path, err);
+                    return Ok((Outcome::Failed, bytes));
+                }
+                log::debug!("Saved {} headers of {} to {}", count, log_url, headers_path);
+            }
+
+            {
+                let mut cache = cache.lock().unwrap();
+                if let Some(etag) = etag {
+                    cache.etags.insert(url.clone(), etag);
+                }
+                if let Some(last_modified) = last_modified {
+                    cache.last_modified.insert(url.clone(), last_modified);
+                }
+                cache.downloaded.insert(url.clone(), Instant::now());
+                cache.touch(url, config.etag_cache_size);
+            }
+
+            let body_len = human_bytes::human_bytes(bytes as f64);
+
+            let normalize = entry
+                .normalize_line_endings
+                .or(config.normalize_line_endings)
+                .unwrap_or(false)
+                && !binary;
+            let ensure_newline = entry
+                .ensure_trailing_newline
+                .or(config.ensure_trailing_newline)
+                .unwrap_or(false)
+                && !binary;
+            // The epoch marks a new file in the diff headers
+            let modified = fs::metadata(path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .map_or(DateTime::UNIX_EPOCH.into(), DateTime::from);
+            // The diff to log and the complete one for `diff_log_path`
+            let diffs =
+                |current: &[u8], body: &[u8], binary: bool, change: &Change| {
+                    let changed = matches!(change, Change::Binary | Change::Text { .. });
+                    (
+                        (config.log_diff && changed)
+                            .then(|| render_diff(path, current, body, binary, modified, config)),
+                        entry.diff_log_path.as_ref().filter(|_| changed).map(|_| {
+                            render_diff_log(path, current, body, binary, modified, config)
+                        }),
+                    )
+                };
+            let (change, (diff, diff_log)) = match (&entry.extract, &streamed) {
+                // Compares the extracted files instead of the archive itself
+                (Some(extract), _) => {
+                    let manifests = tokio::task::block_in_place(|| {
+                        extract::manifests(&body, Path::new(path), extract)
+                    });
+                    // Nothing was extracted yet, so the previous files are kept
+                    let (current, extracted) = match manifests {
+                        Ok(manifests) => manifests,
+                        Err(err) => {
+                            log::error!("Failed to read archive from {}: {:#}", log_url, err);
+                            cache.lock().unwrap().forget(url);
+                            return Ok((Outcome::Failed, bytes));
+                        }
+                    };
+                    let change = detect_change(
+                        &current,
+                        &extracted,
+                        false,
+                        config.diff_algorithm,
+                        detection,
+                    );
+                    (change, diffs(&current, &extracted, false, &change))
+                }
+                (None, Some(streamed)) => {
+                    let change = match current_sha256 {
+                        _ if matches!(detection, ChangeDetection::Always) => Change::Unknown,
+                        Some(current) if current == streamed.sha256 => Change::None,
+                        _ => Change::Binary,
+                    };
+                    (change, diffs(&[], &[], true, &change))
+                }
+                (None, None) => {
+                    let mut current = match detection {
+                        ChangeDetection::Always => Vec::new(),
+                        _ => fs::read(path).await.unwrap_or_default(),
+                    };
+                    if normalize {
+                        current = normalize_line_endings(&current);
+                        body = normalize_line_endings(&body);
+                    }
+                    if ensure_newline && !body.is_empty() && !body.ends_with(b"\n") {
+                        body.push(b'\n');
+                        log::debug!("Appended trailing newline to {}", log_url);
+                    }
+                    let change =
+                        detect_change(&current, &body, binary, config.diff_algorithm, detection);
+                    (change, diffs(&current, &body, binary, &change))
+                }
+            };
+
+            // Of the body as written, after appending a newline
+            let len = streamed
+                .as_ref()
+                .map_or(body.len(), |streamed| streamed.len);
+            if change != Change::None {
+                if !args.dry_run {
+                    // The other paths are still written if one fails
+                    let write_locks = cache.lock().unwrap().write_locks.clone();
+                    let mut failed = false;
+                    for (index, path) in paths.iter().enumerate() {
+                        if let Err(err) = write_output(
+                            path,
+                            &body,
+                            streamed.as_ref(),
+                            index == paths.len() - 1,
+                            entry,
+                            config,
+                            &write_locks,
+                        )
+                        .await
+                        {
+                            log::error!("{:#}", err);
+                            failed = true;
+                        }
+                    }
+                    if failed {
+                        // Downloaded again next time instead of being skipped as not modified
+                        cache.lock().unwrap().forget(url);
+                        return Ok((Outcome::Failed, len));
+                    }
+                    metrics::record_change(&log_url);
+                }
+
+                log::info!(
+                    "{} {} to {} ({}{})",
+                    if args.dry_run {
+                        "Would download"
+                    } else {
+                        "Downloaded"
+                    },
+                    log_url,
+                    paths.join(", "),
+                    body_len,
+                    change,
+                );
+                if let Some(diff) = diff {
+                    log::debug!("Changes in {}:\n{}", path, diff);
+                }
+                if let (Some(diff_log_path), Some(diff)) = (&entry.diff_log_path, diff_log)
+                    && !args.dry_run
+                {
+                    append_diff_log(diff_log_path, &diff).await;
+                }
+
+                if let Some(webhook) = entry.webhook.as_ref().or(config.webhook.as_ref())
+                    && !args.dry_run
+                {
+                    let (additions, removals) = change.counts();
+                    let event = Event {
+                        kind: EventKind::Change,
+                        url: log_url.clone(),
+                        path: path.to_string(),
+                        additions,
+                        removals,
+                        bytes: len,
+                        failures: 0,
+                    };
+                    // Sent in the background so that slow webhooks don't delay downloads
+                    tokio::spawn(webhook::notify(
+                        clients.global.clone(),
+                        webhook.clone(),
+                        event,
+                    ));
+                }
+
+                // Awaited so that the next cycle doesn't start before the command has finished
+                if let Some(cmd) = &entry.on_change
+                    && !args.dry_run
+                {
+                    let (additions, removals) = change.counts();
+                    let mut env = vec![
+                        ("KNOT_URL", log_url.clone()),
+                        ("KNOT_PATH", path.to_string()),
+                        ("KNOT_ADDITIONS", additions.to_string()),
+                        ("KNOT_REMOVALS", removals.to_string()),
+                        ("KNOT_BYTES", len.to_string()),
+                        ("KNOT_CHANGED", "true".to_string()),
+                    ];
+                    if let Some(timeout) = config.on_change_timeout {
+                        env.push(("KNOT_TIMEOUT_SECS", timeout.as_secs().to_string()));
+                    }
+                    if let Err(err) = hooks::run(cmd, &env, config.on_change_timeout).await {
+                        log::error!("{:#}", err);
+                    }
+                }
+
+                Outcome::Updated
+            } else {
+                log::debug!(
+                    "Skipped {} ({})",
+                    log_url,
+                    match detection {
+                        ChangeDetection::Hash => "unchanged, hash match",
+                        _ if streamed.is_some() => "unchanged, hash match",
+                        _ => "no changes",
+                    }
+                );
+                Outcome::Unchanged
+            }
+        }
+        (_, Some(Err(err))) => {
+            log::error!("Failed to download {}{}: {:#}", log_url, retried, err);
+            Outcome::Failed
+        }
+        (Ok(resp), _) if resp.status() == StatusCode::NOT_MODIFIED => {
+            // A partial file left over from before the current version was downloaded
+            if let Some(part_path) = &part_path {
+                remove_part(part_path).await;
+            }
+            log::debug!(
+                "Skipped {} (not modified, {})",
+                log_url,
+                validator.unwrap_or("unconditional"),
+            );
+            Outcome::Unchanged
+        }
+        (Ok(resp), _) => {
+            log::error!(
+                "Failed to download {}{}: {}",
+                log_url,
+                retried,
+                resp.status()
+            );
+            Outcome::Failed
+        }
+        (Err(err), _) => {
+            log::error!("Failed to download {}{}: {}", log_url, retried, err);
+            Outcome::Failed
+        }
+    };
+
+    if let Some(link) = &entry.symlink_to
+        && matches!(outcome, Outcome::Updated | Outcome::Unchanged)
+        && !args.dry_run
+        && let Err(err) = update_symlink(link, path).await
+    {
+        log::error!("Failed to link {} to {}: {:#}", link, path, err);
+    }
+
+    Ok((outcome, bytes))
+}
+
+/// Returns how long to wait before downloading the entry again.
+fn next_delay(entry: &FileEntry, config: &Config) -> Result<Duration> {
+    match &entry.cron {
+        Some(cron) => until_next_run(cron),
+        None => {
+            let interval = entry.interval.unwrap_or(config.interval);
+            let jitter = entry.jitter.or(config.jitter).unwrap_or(0.0);
+            Ok(interval.mul_f64(1.0 + rand::random::<f64>() * jitter))
+        }
+    }
+}
+
+/// Logs when a URL recovers, notifying `on_recovery_webhook`. Webhooks with `notify_failures` are
+/// notified once a URL reached `failure_alert_after` consecutive failures, and once it recovers
+/// from them.
+fn notify_failure(
+    client: &Client,
+    config: &Config,
+    entry: &FileEntry,
+    outcome: Outcome,
+    failures_before: u32,
+    alerted: &mut HashSet<String>,
+) {
+    let url = entry.url();
+    let recovered = matches!(outcome, Outcome::Updated | Outcome::Unchanged) && failures_before > 0;
+    let event = |kind, failures| Event {
+        kind,
+        url: redact_url(url),
+        path: entry.paths[0].clone(),
+        additions: 0,
+        removals: 0,
+        bytes: 0,
+        failures,
+    };
+    if recovered {
+        log::info!(
+            "{} recovered after {} consecutive failures",
+            redact_url(url),
+            failures_before
+        );
+        if let Some(webhook) = &config.on_recovery_webhook {
+            let event = event(EventKind::Recovery, failures_before);
+            tokio::spawn(webhook::notify(client.clone(), webhook.clone(), event));
+        }
+    }
+
+    let Some(webhook) = entry
+        .webhook
+        .as_ref()
+        .or(config.webhook.as_ref())
+        .filter(|webhook| webhook.notify_failures.unwrap_or(false))
+    else {
+        return;
+    };
+    let failures = failures_before + 1;
+    let event = match outcome {
+        Outcome::Failed if failures == config.failure_alert_after.unwrap_or(1) => {
+            alerted.insert(url.to_string());
+            event(EventKind::Failure, failures)
+        }
+        Outcome::Failed if alerted.contains(url) => {
+            log::debug!(
+                "Not notifying about failure {} of {} again",
+                failures,
+                redact_url(url)
+            );
+            return;
+        }
+        Outcome::Updated | Outcome::Unchanged if alerted.remove(url) => {
+            event(EventKind::Recovery, failures_before)
+        }
+        _ => return,
+    };
+    tokio::spawn(webhook::notify(client.clone(), webhook.clone(), event));
+}
+
+/// Downloads all files whenever they are due. With `--once` or `--dry-run`, every file is
+/// downloaded a single time and the outcomes are returned instead of looping forever.
+///
+/// A new config sent through `configs` is picked up before the next cycle, keeping the cache.
+/// `bootstrapped` is sent once every enabled entry was downloaded successfully. Once `shutdown`
+/// is cancelled, no more downloads are started and the function returns after the running ones.
+async fn download_files(
+    mut configs: watch::Receiver<Arc<Config>>,
+    mut args: Args,
+    cache: Arc<Mutex<Cache>>,
+    status: Arc<Mutex<Status>>,
+    cookies: Arc<CookieStoreMutex>,
+    mut bootstrapped: Option<oneshot::Sender<()>>,
+    shutdown: CancellationToken,
+) -> Result<Vec<Outcome>> {
+    let mut breakers: HashMap<String, CircuitBreaker> = HashMap::new();
+    // URLs whose failure was notified, which are notified again once they recover
+    let mut alerted: HashSet<String> = HashSet::new();
+    // URLs downloaded successfully at least once, until every entry was
+    let mut succeeded: HashSet<String> = HashSet::new();
+    let mut startup = true;
+
+    // Kept to continue with if the client of a reloaded config can't be built
+    let mut previous: Option<(Arc<Config>, Client)> = None;
+
+    loop {
+        let mut config = configs.borrow_and_update().clone();
+        let client = match (build_client(&config, None, &cookies), previous.take()) {
+            (Ok(client), _) => client,
+            (Err(err), Some((previous_config, previous_client))) => {
+                log::error!("Keeping the previous config: {:#}", err);
+                config = previous_config;
+                previous_client
+            }
+            (Err(err), None) => return Err(err),
+        };
+        previous = Some((config.clone(), client.clone()));
+        let failure_threshold = config.failure_threshold.unwrap_or(0);
+        let failure_cooldown = config.failure_cooldown.unwrap_or(DEFAULT_FAILURE_COOLDOWN);
+        // Built when first needed and kept until the config is reloaded
+        let mut entry_clients: HashMap<String, Client> = HashMap::new();
+        let client_keys: HashSet<_> = config
+            .files
+            .iter()
+            .filter(|entry| needs_own_client(entry))
+            .map(client_key)
+            .collect();
+        if !client_keys.is_empty() {
+            log::info!(
+                "Using {} distinct connection settings (TLS, proxy, redirects) besides the global ones",
+                client_keys.len()
+            );
+        }
+        // Tokio's semaphore is fair (FIFO), so no entry waits behind later ones
+        let concurrency = config.concurrency.unwrap_or(1).max(1);
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let rate_limiter = config
+            .rate_limit
+            .as_ref()
+            .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit)));
+        let presigner = if config
+            .files
+            .iter()
+            .any(|entry| entry.urls.iter().any(|url| s3::is_s3_url(url)))
+        {
+            Some(Arc::new(
+                Presigner::new(config.s3_endpoint.as_deref()).await,
+            ))
+        } else {
+            None
+        };
+        log::debug!("Downloading up to {} files at a time", concurrency);
+        let mut next_due = vec![Instant::now(); config.files.len()];
+        if startup
+            && !config.download_on_startup
+            && bootstrapped.is_none()
+            && !(args.once || args.dry_run)
+        {
+            for (due, entry) in next_due.iter_mut().zip(&config.files) {
+                *due += next_delay(entry, &config)?;
+            }
+        }
+        startup = false;
+        // A reload doesn't cut the cooldown of paused URLs short
+        for (due, entry) in next_due.iter_mut().zip(&config.files) {
+            if let Some(until) = breakers
+                .get(entry.url())
+                .and_then(|breaker| breaker.open_until)
+            {
+                *due = (*due).max(until);
+            }
+        }
+
+        let enabled: Vec<_> = config
+            .files
+            .iter()
+            .map(|entry| entry.enabled.unwrap_or(true))
+            .collect();
+        let disabled: Vec<_> = config
+            .files
+            .iter()
+            .zip(&enabled)
+            .filter(|&(_, &enabled)| !enabled)
+            .map(|(entry, _)| redact_url(entry.url()))
+            .collect();
+        if !disabled.is_empty() {
+            log::debug!("Disabled entries: {}", disabled.join(", "));
+        }
+
+        // Sorted for every config, since reloads may change the priorities
+        let mut order: Vec<_> = (0..config.files.len()).collect();
+        order.sort_by_key(|&index| Reverse(config.files[index].priority.unwrap_or(0)));
+        log::trace!(
+            "Download order: {}",
+            order
+                .iter()
+                .map(|&index| redact_url(config.files[index].url()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        loop {
+            let mut tasks = JoinSet::new();
+            // To tell which entry a panicked task downloaded
+            let mut indices = HashMap::new();
+            let started = Instant::now();
+
+            for &index in &order {
+                if shutdown.is_cancelled() {
+                    break;
+                }
+                let due = &mut next_due[index];
+                let now = Instant::now();
+                if !enabled[index] || now < *due {
+                    continue;
+                }
+                let entry = &config.files[index];
+                *due = now + next_delay(entry, &config)?;
+
+                if breakers
+                    .get(entry.url())
+                    .is_some_and(|breaker| breaker.open_until.is_some())
+                {
+                    log::warn!("Retrying {} after cooldown", redact_url(entry.url()));
+                }
+
+                // Acquire the permit before spawning so that downloads start in config order
+                let permit = tokio::select! {
+                    permit = semaphore.clone().acquire_owned() => permit?,
+                    _ = shutdown.cancelled() => break,
+                };
+                let entry_client = if needs_own_client(entry) {
+                    match entry_clients.entry(client_key(entry)) {
+                        hash_map::Entry::Occupied(client) => Ok(client.get().clone()),
+                        hash_map::Entry::Vacant(slot) => {
+                            build_client(&config, Some(entry), &cookies)
+                                .map(|client| slot.insert(client).clone())
+                        }
+                    }
+                } else {
+                    Ok(client.clone())
+                };
+                // E.g. a certificate removed since the config was validated, which fails only
+                // this entry until it can be built
+                let entry_client = match entry_client {
+                    Ok(client) => client,
+                    Err(err) => {
+                        log::error!("Failed to download {}: {:#}", redact_url(entry.url()), err);
+                        let task = tasks.spawn(async move { (index, Ok((Outcome::Failed, 0))) });
+                        indices.insert(task.id(), index);
+                        continue;
+                    }
+                };
+                let clients = Clients {
+                    entry: entry_client,
+                    global: client.clone(),
+                };
+                let (config, cache, rate_limiter, presigner) = (
+                    config.clone(),
+                    cache.clone(),
+                    rate_limiter.clone(),
+                    presigner.clone(),
+                );
+
+                let task = tasks.spawn(async move {
+                    let _permit = permit;
+                    let entry = &config.files[index];
+                    let outcome = download_file(
+                        &clients,
+                        entry,
+                        &config,
+                        args,
+                        &cache,
+                        rate_limiter.as_deref(),
+                        presigner.as_deref(),
+                    )
+                    .await;
+                    (index, outcome)
+                });
+                indices.insert(task.id(), index);
+            }
+
+            let mut outcomes = Vec::with_capacity(tasks.len());
+            let mut stats = CycleStats::default();
+            while let Some(res) = tasks.join_next_with_id().await {
+                // Count as failed downloads, so that the other entries carry on
+                let (index, (outcome, bytes)) = match res {
+                    Ok((_, (index, Ok(outcome)))) => (index, outcome),
+                    Ok((_, (index, Err(err)))) => {
+                        let url = redact_url(config.files[index].url());
+                        log::error!("Failed to download {}: {:#}", url, err);
+                        (index, (Outcome::Failed, 0))
+                    }
+                    Err(err) => {
+                        let index = indices[&err.id()];
+                        let url = redact_url(config.files[index].url());
+                        log::error!("Download of {} panicked: {}", url, err);
+                        (index, (Outcome::Failed, 0))
+                    }
+                };
+                outcomes.push(outcome);
+                stats.record(outcome, bytes);
+                let failures_before = status
+                    .lock()
+                    .unwrap()
+                    .record(config.files[index].url(), outcome);
+                if bootstrapped.is_some() {
+                    let url = config.files[index].url();
+                    if matches!(outcome, Outcome::Updated | Outcome::Unchanged) {
+                        succeeded.insert(url.to_string());
+                    } else if !succeeded.contains(url) {
+                        let delay = backoff(failures_before, &config);
+                        log::debug!("Retrying {} in {:?} to bootstrap", redact_url(url), delay);
+                        next_due[index] = next_due[index].min(Instant::now() + delay);
+                    }
+                }
+                if !args.dry_run {
+                    notify_failure(
+                        &client,
+                        &config,
+                        &config.files[index],
+                        outcome,
+                        failures_before,
+                        &mut alerted,
+                    );
+                }
+
+                // A failed `before_download` says nothing about the URL
+                if failure_threshold == 0 || outcome == Outcome::Skipped {
+                    continue;
+                }
+                let url = config.files[index].url();
+                let breaker = breakers.entry(url.to_string()).or_default();
+                if outcome != Outcome::Failed {
+                    if breaker.open_until.is_some() {
+                        log::warn!("Resuming {} after it recovered", redact_url(url));
+                    }
+                    *breaker = CircuitBreaker::default();
+                    continue;
+                }
+
+                breaker.failures += 1;
+                if breaker.failures >= failure_threshold {
+                    log::warn!(
+                        "Pausing {} for {:?} after {} consecutive failures",
+                        redact_url(url),
+                        failure_cooldown,
+                        breaker.failures,
+                    );
+                    let until = Instant::now() + failure_cooldown;
+                    breaker.open_until = Some(until);
+                    next_due[index] = next_due[index].max(until);
+                }
+            }
+
+            if bootstrapped.is_some()
+                && config
+                    .files
+                    .iter()
+                    .zip(&enabled)
+                    .all(|(entry, &enabled)| !enabled || succeeded.contains(entry.url()))
+            {
+                log::info!("Downloaded every file at least once");
+                let _ = bootstrapped
+                    .take()
+                    .map(|bootstrapped| bootstrapped.send(()));
+                succeeded.clear();
+            }
+
+            if stats.checked > 0 {
+                log::info!("{} in {:?}", stats, started.elapsed());
+                let mut status = status.lock().unwrap();
+                status.cycles += 1;
+                status.last_cycle = Some(Instant::now());
+                // Also sent after failed downloads, as the loop itself is still running
+                systemd::watchdog();
+            }
+            if let Some(path) = &config.state_file
+                && stats.checked > 0
+                && !args.dry_run
+            {
+                save_state(path, &cache).await;
+            }
+
+            if args.once || args.dry_run || shutdown.is_cancelled() {
+                return Ok(outcomes);
+            }
+            args.force = false;
+
+            // Sleep until the earliest entry is due again, which includes its random jitter
+            let next = next_due
+                .iter()
+                .zip(&enabled)
+                .filter(|&(_, &enabled)| enabled)
+                .map(|(&due, _)| due)
+                .min()
+                .unwrap_or_else(|| Instant::now() + config.interval);
+            log::debug!(
+                "Sleeping for {:?}",
+                next.saturating_duration_since(Instant::now())
+            );
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => {}
+                res = configs.changed() => {
+                    res.context("Config channel closed")?;
+                    break;
+                }
+                _ = shutdown.cancelled() => return Ok(outcomes),
+            }
+        }
+    }
+}
+
+async fn serve_metrics(addr: Option<&str>) -> Result<()> {
+    match addr {
+        Some(addr) => metrics::serve(addr).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn serve_health(
+    addr: Option<&str>,
+    configs: watch::Receiver<Arc<Config>>,
+    cache: Arc<Mutex<Cache>>,
+    status: Arc<Mutex<Status>>,
+) -> Result<()> {
+    match addr {
+        Some(addr) => health::serve(addr, configs, cache, status).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Expands `CONFIG_PATH`, a list of paths or glob patterns separated like `PATH`, into files.
+fn config_paths(spec: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+
+    for pattern in env::split_paths(spec) {
+        let pattern = pattern.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(PathBuf::from(pattern.as_ref()));
+            continue;
+        }
+
+        let matches = glob::glob(&pattern)
+            .with_context(|| format!("Invalid config path pattern {pattern:?}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to expand config path pattern {pattern:?}"))?;
+        if matches.is_empty() {
+            anyhow::bail!("No config files match {pattern:?}");
+        }
+        paths.extend(matches);
+    }
+
+    Ok(paths)
+}
+
+async fn read_config<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let config = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read config file from {path:?}"))?;
+    parse_config(path, &config)
+        .with_context(|| format!("Failed to parse config file from {path:?}"))
+}
+
+/// Loads the primary config file and appends the files of all others to it.
+async fn load_config(spec: &str) -> Result<Config> {
+    let paths = config_paths(spec)?;
+    let (primary, fragments) = paths.split_first().context("No config file given")?;
+
+    let mut config: Config = read_config(primary).await?;
+    for path in fragments {
+        let fragment: ConfigFragment = read_config(path).await?;
+        config.files.extend(fragment.files);
+    }
+
+    let errors = validate_config(&config);
+    if !errors.is_empty() {
+        let errors: Vec<_> = errors.iter().map(|err| format!("    - {err}")).collect();
+        anyhow::bail!("Invalid config:\n{}", errors.join("\n"));
+    }
+
+    Ok(config)
+}
+
+fn warn_insecure_tls(config: &Config) {
+    let urls: Vec<_> = config
+        .files
+        .iter()
+        .filter(|entry| entry.tls_insecure.or(config.tls_insecure).unwrap_or(false))
+        .map(|entry| redact_url(entry.url()))
+        .collect();
+    if !urls.is_empty() {
+        log::warn!(
+            "TLS certificate verification is DISABLED (`tls_insecure`) for: {}",
+            urls.join(", ")
+        );
+    }
+}
+
+/// Warns about destinations on another filesystem than `temp_dir`, which are copied into place
+/// instead of renamed atomically.
+fn warn_temp_dir_filesystems(config: &Config) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let Some(temp_dir) = config.temp_dir.as_ref().filter(|_| config.atomic_writes) else {
+            return;
+        };
+        let Ok(temp_dev) = std::fs::metadata(temp_dir).map(|metadata| metadata.dev()) else {
+            return;
+        };
+        let paths: Vec<_> = config
+            .files
+            .iter()
+            .filter(|entry| entry.extract.is_none())
+            .flat_map(|entry| &entry.paths)
+            .filter(|path| {
+                let parent = Path::new(path)
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."));
+                std::fs::metadata(parent).is_ok_and(|metadata| metadata.dev() != temp_dev)
+            })
+            .map(String::as_str)
+            .collect();
+        if !paths.is_empty() {
+            log::warn!(
+                "`temp_dir` {:?} is on another filesystem, so these files are written non-atomically: {}",
+                temp_dir,
+                paths.join(", ")
+            );
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = config;
+}
+
+fn warn_duplicate_entries(config: &Config) {
+    let mut seen = HashSet::new();
+    for entry @ FileEntry { urls, paths, .. } in &config.files {
+        if !seen.insert((urls, paths)) {
+            log::warn!(
+                "Duplicate entry for {} to {}",
+                redact_url(entry.url()),
+                paths.join(", ")
+            );
+        }
+    }
+}
+
+async fn create_directories(config: &Config) -> Result<()> {
+    for entry in &config.files {
+        if !entry
+            .create_directories
+            .unwrap_or(config.create_directories)
+        {
+            continue;
+        }
+        for path in entry.paths.iter().filter(|path| !path.contains("{{")) {
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("Failed to create directories for {path:?}"))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn reload_config(path: &str, configs: &watch::Sender<Arc<Config>>) -> Result<()> {
+    let config = load_config(path).await?;
+    warn_duplicate_entries(&config);
+    warn_insecure_tls(&config);
+    create_directories(&config).await?;
+    warn_temp_dir_filesystems(&config);
+    configs.send_replace(Arc::new(config));
+
+    Ok(())
+}
+
+async fn run() -> Result<ExitCode> {
+    let args = parse_args()?;
+
+    #[cfg(windows)]
+    if let Some(command) = args.service {
+        return service::handle(command);
+    }
+
+    let config_path = env::var("CONFIG_PATH").unwrap_or("config.yml".to_string());
+    serve(args, &config_path, wait_for_shutdown_signal(), None).await
+}
+
+/// Downloads files until `shutdown` completes. As a Windows service, `running` is notified once
+/// the downloads started, and logs are sent to the Event Log unless another `log_target` is
+/// configured.
+async fn serve(
+    args: Args,
+    config_path: &str,
+    shutdown: impl Future<Output = Result<()>>,
+    running: Option<oneshot::Sender<()>>,
+) -> Result<ExitCode> {
+    let mut config = load_config(config_path).await?;
+    if running.is_some() && matches!(config.log_target, LogTarget::Stdout) {
+        config.log_target = LogTarget::EventLog;
+    }
+
+    if args.check {
+        println!("Config file {config_path:?} is valid");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    setup_logger(&config)?;
+    warn_duplicate_entries(&config);
+    warn_insecure_tls(&config);
+
+    if !args.dry_run {
+        create_directories(&config).await?;
+        warn_temp_dir_filesystems(&config);
+    }
+
+    let metrics_addr = config.metrics_addr.clone();
+    let health_addr = config.health_addr.clone();
+    let cache = match &config.state_file {
+        Some(path) => load_state(path).await,
+        None => Cache::default(),
+    };
+    let cache = Arc::new(Mutex::new(cache));
+    let cookie_store_path = config.cookie_store_path.clone();
+    let cookies = cookies::load(cookie_store_path.as_deref()).await;
+    let (configs, config_rx) = watch::channel(Arc::new(config));
+    let status = Arc::new(Mutex::new(Status::new()));
+
+    if args.once || args.dry_run {
+        let outcomes = download_files(
+            config_rx,
+            args,
+            cache,
+            status,
+            cookies.clone(),
+            None,
+            CancellationToken::new(),
+        )
+        .await?;
+        if let Some(path) = &cookie_store_path
+            && !args.dry_run
+        {
+            cookies::save(path, &cookies).await;
+        }
+        if outcomes.contains(&Outcome::Failed) || outcomes.contains(&Outcome::Skipped) {
+            return Ok(ExitCode::from(1));
+        }
+        if args.dry_run && outcomes.contains(&Outcome::Updated) {
+            return Ok(ExitCode::from(2));
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let pid_file = configs.borrow().pid_file.clone();
+    if let Some(path) = &pid_file {
+        pid_file::create(path)?;
+    }
+
+    let health = serve_health(
+        health_addr.as_deref(),
+        config_rx.clone(),
+        cache.clone(),
+        status.clone(),
+    );
+    let (bootstrapped_tx, mut bootstrapped) = oneshot::channel();
+    let (bootstrap_required, bootstrap_timeout) = {
+        let config = configs.borrow();
+        (config.bootstrap_required, config.bootstrap_timeout)
+    };
+    let stop_downloads = CancellationToken::new();
+    let downloads = download_files(
+        config_rx,
+        args,
+        cache.clone(),
+        status.clone(),
+        cookies.clone(),
+        bootstrap_required.then_some(bootstrapped_tx),
+        stop_downloads.clone(),
+    );
+    let metrics = serve_metrics(metrics_addr.as_deref());
+    let bootstrap_deadline = async {
+        match bootstrap_timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(downloads, metrics, health, shutdown, bootstrap_deadline);
+
+    let mut reload = ReloadSignal::new()?;
+    let mut status_signal = StatusSignal::new()?;
+    let mut bootstrapping = bootstrap_required;
+    if !bootstrapping {
+        systemd::ready();
+    }
+    if let Some(running) = running {
+        let _ = running.send(());
+    }
+
+    loop {
+        tokio::select! {
+            res = &mut downloads => { res?; }
+            res = &mut bootstrapped, if bootstrapping => {
+                res.context("Downloads stopped before bootstrapping")?;
+                bootstrapping = false;
+                systemd::ready();
+            }
+            _ = &mut bootstrap_deadline, if bootstrapping => {
+                log::error!(
+                    "Not every file was downloaded within `bootstrap_timeout` ({:?})",
+                    bootstrap_timeout.unwrap_or_default()
+                );
+                if let Some(path) = &pid_file {
+                    pid_file::remove(path);
+                }
+                return Ok(ExitCode::from(1));
+            }
+            res = &mut metrics => { res?; }
+            res = &mut health => { res?; }
+            res = reload.recv() => {
+                res?;
+                // Logging and metrics settings are only applied at startup
+                match reload_config(config_path, &configs).await {
+                    Ok(()) => log::info!("Reloaded config from {:?}", config_path),
+                    Err(err) => log::error!("Failed to reload config: {:#}", err),
+                }
+            }
+            res = status_signal.recv() => {
+                res?;
+                // Printed directly so that it is not wrapped in log formatting
+                let cache = cache.lock().unwrap();
+                println!("{}", status.lock().unwrap().to_json(&cache));
+            }
+            res = &mut shutdown => {
+                res?;
+                systemd::stopping();
+                log::warn!("Shutting down...");
+                // Lets running downloads finish writing their files, which cancelling them could
+                // interrupt
+                stop_downloads.cancel();
+                let timeout = configs.borrow().shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+                match tokio::time::timeout(timeout, &mut downloads).await {
+                    Ok(res) => {
+                        res?;
+                    }
+                    Err(_) => log::warn!("Cancelled downloads still running after {:?}", timeout),
+                }
+                if let Some(path) = &cookie_store_path {
+                    cookies::save(path, &cookies).await;
+                }
+                if let Some(path) = &pid_file {
+                    pid_file::remove(path);
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    // Force colored output in Docker environments
+    colored::control::set_override(true);
+
+    match run().await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            if err.chain().len() > 1 {
+                eprintln!("\nCaused by:");
+            }
+            for cause in err.chain().skip(1) {
+                eprintln!("  {cause}");
+            }
+            ExitCode::from(1)
+        }
+    }
 }
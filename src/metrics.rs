@@ -0,0 +1,141 @@
+use std::{convert::Infallible, sync::LazyLock, time::Duration};
+
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode, body::Bytes, header, server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+
+struct Metrics {
+    registry: Registry,
+    downloads: IntCounterVec,
+    bytes: IntCounterVec,
+    changes: IntCounterVec,
+    duration: HistogramVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+
+    let downloads = IntCounterVec::new(
+        Opts::new("knot_download_total", "Number of download attempts"),
+        &["url", "status"],
+    )
+    .unwrap();
+    let bytes = IntCounterVec::new(
+        Opts::new("knot_download_bytes_total", "Number of bytes downloaded"),
+        &["url"],
+    )
+    .unwrap();
+    let changes = IntCounterVec::new(
+        Opts::new(
+            "knot_file_changed_total",
+            "Number of times a file was updated",
+        ),
+        &["url"],
+    )
+    .unwrap();
+    let duration = HistogramVec::new(
+        HistogramOpts::new(
+            "knot_download_duration_seconds",
+            "Time spent downloading a file, including retries",
+        ),
+        &["url"],
+    )
+    .unwrap();
+
+    registry.register(Box::new(downloads.clone())).unwrap();
+    registry.register(Box::new(bytes.clone())).unwrap();
+    registry.register(Box::new(changes.clone())).unwrap();
+    registry.register(Box::new(duration.clone())).unwrap();
+
+    Metrics {
+        registry,
+        downloads,
+        bytes,
+        changes,
+        duration,
+    }
+});
+
+/// Records a finished request with its HTTP status code (or `error` if no response was received).
+pub fn record_download(url: &str, status: &str) {
+    METRICS.downloads.with_label_values(&[url, status]).inc();
+}
+
+/// Records a successfully received response body.
+pub fn record_body(url: &str, len: usize, duration: Duration) {
+    METRICS.bytes.with_label_values(&[url]).inc_by(len as u64);
+    METRICS
+        .duration
+        .with_label_values(&[url])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn record_change(url: &str) {
+    METRICS.changes.with_label_values(&[url]).inc();
+}
+
+fn render() -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buf)
+        .context("Failed to encode metrics")?;
+
+    Ok(buf)
+}
+
+async fn handle(req: Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => match render() {
+            Ok(body) => (StatusCode::OK, body),
+            Err(err) => {
+                log::error!("{:#}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, Vec::new())
+            }
+        },
+        _ => (StatusCode::NOT_FOUND, Vec::new()),
+    };
+
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+
+    Ok(resp)
+}
+
+/// Serves the `/metrics` endpoint in the Prometheus text format until the future is dropped.
+pub async fn serve(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics server to {addr:?}"))?;
+    log::info!("Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("Failed to accept metrics connection: {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(handle))
+                .await
+            {
+                log::debug!("Failed to serve metrics to {}: {}", peer, err);
+            }
+        });
+    }
+}
@@ -0,0 +1,98 @@
+use std::{fmt, sync::Mutex, time::Duration};
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::{redact_url, resolve_secret};
+
+/// Tokens are fetched again when they expire within this margin.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// OAuth2 client credentials grant, whose access token is sent as bearer token.
+#[derive(Deserialize)]
+pub struct OAuth2Config {
+    pub token_url: String,
+    pub client_id: String,
+    /// Either a literal secret or `$NAME` to read it from the environment variable `NAME`.
+    pub client_secret: String,
+    pub scope: Option<String>,
+    /// Cached until it is about to expire, or until the config is reloaded.
+    #[serde(skip)]
+    token: Mutex<Option<Token>>,
+}
+
+impl fmt::Debug for OAuth2Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuth2Config")
+            .field("token_url", &redact_url(&self.token_url))
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"***")
+            .field("scope", &self.scope)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Token {
+    access_token: String,
+    /// `None` if the server didn't say when the token expires.
+    expires_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl OAuth2Config {
+    /// Returns the cached access token, fetching a new one from `token_url` if needed.
+    pub async fn token(&self, client: &Client) -> Result<String> {
+        if let Some(token) = &*self.token.lock().unwrap()
+            && token
+                .expires_at
+                .is_none_or(|expires_at| Instant::now() + REFRESH_MARGIN < expires_at)
+        {
+            return Ok(token.access_token.clone());
+        }
+
+        let client_secret = resolve_secret(&self.client_secret)?;
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &client_secret),
+        ];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+
+        let log_url = redact_url(&self.token_url);
+        let requested = Instant::now();
+        let resp: TokenResponse = client
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(reqwest::Error::without_url)
+            .with_context(|| format!("Failed to fetch OAuth2 token from {log_url:?}"))?
+            .json()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .with_context(|| format!("Invalid OAuth2 token response from {log_url:?}"))?;
+
+        let expires_in = resp.expires_in.map(Duration::from_secs);
+        log::debug!(
+            "Fetched OAuth2 token from {} (expires in {:?})",
+            log_url,
+            expires_in
+        );
+        *self.token.lock().unwrap() = Some(Token {
+            access_token: resp.access_token.clone(),
+            expires_at: expires_in.map(|expires_in| requested + expires_in),
+        });
+
+        Ok(resp.access_token)
+    }
+}
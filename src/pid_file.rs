@@ -0,0 +1,72 @@
+use std::{fs, io, process};
+
+use anyhow::{Context, Result};
+
+/// Whether a process with the PID is still running.
+fn is_running(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        std::path::Path::new(&format!("/proc/{pid}/status")).exists()
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::{
+            Foundation::{CloseHandle, STILL_ACTIVE},
+            System::Threading::{
+                GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+            },
+        };
+
+        // SAFETY: The handle is checked before use and closed afterwards
+        unsafe {
+            let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+            if handle.is_null() {
+                return false;
+            }
+            let mut exit_code = 0;
+            let running =
+                GetExitCodeProcess(handle, &mut exit_code) != 0 && exit_code == STILL_ACTIVE as u32;
+            CloseHandle(handle);
+            running
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    {
+        process::Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+}
+
+/// Writes the PID of this process to `path`, failing if the file belongs to another process that
+/// is still running. Stale files, e.g. after a crash, are replaced.
+pub fn create(path: &str) -> Result<()> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            if let Ok(pid) = contents.trim().parse::<u32>()
+                && pid != process::id()
+                && is_running(pid)
+            {
+                anyhow::bail!("Already running with PID {pid} (from PID file {path:?})");
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("Failed to read PID file {path:?}")),
+    }
+
+    fs::write(path, process::id().to_string())
+        .with_context(|| format!("Failed to write PID file {path:?}"))?;
+    log::debug!("Wrote PID file {}", path);
+
+    Ok(())
+}
+
+pub fn remove(path: &str) {
+    if let Err(err) = fs::remove_file(path) {
+        log::warn!("Failed to remove PID file {:?}: {}", path, err);
+    }
+}
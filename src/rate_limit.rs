@@ -0,0 +1,73 @@
+use std::{sync::Mutex, time::Duration};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    /// Number of requests that may be sent at once after a quiet period, `1` by default.
+    pub burst: Option<u32>,
+}
+
+/// Token bucket shared by all downloads, refilled continuously at `requests_per_second`.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let burst = f64::from(config.burst.unwrap_or(1).max(1));
+        Self {
+            rate: config.requests_per_second,
+            burst,
+            bucket: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Waits until a request may be sent, returning how long it was delayed.
+    pub async fn acquire(&self) -> Option<Duration> {
+        let delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, updated) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * self.rate)
+                .min(self.burst)
+                - 1.0;
+            *updated = now;
+
+            // A negative balance reserves the token, so concurrent callers queue up behind it
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / self.rate))
+        }?;
+
+        tokio::time::sleep(delay).await;
+        Some(delay)
+    }
+}
+
+/// Limits the throughput of a single download to `bytes_per_second`.
+pub struct Throttle {
+    rate: f64,
+    started: Instant,
+    received: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            rate: bytes_per_second as f64,
+            started: Instant::now(),
+            received: 0,
+        }
+    }
+
+    /// Waits after receiving a chunk until the average throughput is back at the limit. Time spent
+    /// receiving counts, so the delays are shorter on links that are already slow.
+    pub async fn consume(&mut self, len: usize) {
+        self.received += len as u64;
+        let due = self.started + Duration::from_secs_f64(self.received as f64 / self.rate);
+        tokio::time::sleep_until(due).await;
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+
+/// Validity of presigned URLs, which are created right before each request.
+#[cfg(feature = "s3")]
+const PRESIGN_EXPIRY: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+pub fn is_s3_url(url: &str) -> bool {
+    url.starts_with("s3://")
+}
+
+/// Splits an `s3://bucket/key` URL into its bucket and key.
+pub fn parse_url(url: &str) -> Result<(&str, &str)> {
+    let (bucket, key) = url
+        .strip_prefix("s3://")
+        .and_then(|path| path.split_once('/'))
+        .context("Expected s3://bucket/key")?;
+    if bucket.is_empty() || key.is_empty() {
+        anyhow::bail!("Expected s3://bucket/key");
+    }
+
+    Ok((bucket, key))
+}
+
+/// Creates presigned HTTPS URLs for objects so that they are downloaded like any other URL,
+/// including conditional requests with the object's `ETag`.
+pub struct Presigner {
+    #[cfg(feature = "s3")]
+    client: aws_sdk_s3::Client,
+}
+
+impl Presigner {
+    /// Uses the standard AWS credential chain (environment, `~/.aws/credentials`, instance
+    /// metadata) and `endpoint` with path-style addressing for S3-compatible servers.
+    #[cfg(feature = "s3")]
+    pub async fn new(endpoint: Option<&str>) -> Self {
+        use aws_config::{BehaviorVersion, Region};
+        use aws_smithy_http_client::tls::{self, rustls_provider::CryptoMode};
+
+        // Used for instance metadata and STS, with the same rustls backend as reqwest
+        let http_client = aws_smithy_http_client::Builder::new()
+            .tls_provider(tls::Provider::Rustls(CryptoMode::Ring))
+            .build_https();
+        let sdk_config = aws_config::defaults(BehaviorVersion::latest())
+            .http_client(http_client)
+            .load()
+            .await;
+
+        let mut config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        // Presigning needs a region even though S3-compatible servers usually ignore it
+        if sdk_config.region().is_none() {
+            config = config.region(Region::from_static("us-east-1"));
+        }
+        if let Some(endpoint) = endpoint {
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        Self {
+            client: aws_sdk_s3::Client::from_conf(config.build()),
+        }
+    }
+
+    #[cfg(not(feature = "s3"))]
+    pub async fn new(_endpoint: Option<&str>) -> Self {
+        Self {}
+    }
+
+    #[cfg(feature = "s3")]
+    pub async fn presign(&self, url: &str) -> Result<String> {
+        use aws_sdk_s3::presigning::PresigningConfig;
+
+        let (bucket, key) = parse_url(url)?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .presigned(PresigningConfig::expires_in(PRESIGN_EXPIRY)?)
+            .await
+            .context("Failed to presign S3 request")?;
+
+        Ok(request.uri().to_string())
+    }
+
+    #[cfg(not(feature = "s3"))]
+    pub async fn presign(&self, _url: &str) -> Result<String> {
+        anyhow::bail!("S3 support is disabled (enable the `s3` feature)")
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    process::ExitCode,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    runtime::Handle,
+    sync::{Notify, oneshot},
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::Args;
+
+/// Name of the service, which is also used as the Event Log source.
+pub const SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceCommand {
+    Install,
+    Uninstall,
+    Start,
+    Stop,
+    /// Entry point used by the service control manager.
+    Run,
+}
+
+impl FromStr for ServiceCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "install" => Ok(Self::Install),
+            "uninstall" => Ok(Self::Uninstall),
+            "start" => Ok(Self::Start),
+            "stop" => Ok(Self::Stop),
+            "run" => Ok(Self::Run),
+            _ => Err(format!(
+                "Unknown service command {s:?} (expected install, uninstall, start or stop)"
+            )),
+        }
+    }
+}
+
+/// Runtime of `main`, on which the service runs while the dispatcher blocks the main thread.
+static RUNTIME: OnceLock<Handle> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+pub fn handle(command: ServiceCommand) -> Result<ExitCode> {
+    let manager_access = match command {
+        ServiceCommand::Install => {
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE
+        }
+        ServiceCommand::Run => {
+            let _ = RUNTIME.set(Handle::current());
+            tokio::task::block_in_place(|| {
+                service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            })
+            .context("Failed to start service dispatcher")?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => ServiceManagerAccess::CONNECT,
+    };
+    let manager = ServiceManager::local_computer(None::<&str>, manager_access)
+        .context("Failed to connect to the service manager")?;
+
+    match command {
+        ServiceCommand::Install => {
+            let info = ServiceInfo {
+                name: OsString::from(SERVICE_NAME),
+                display_name: OsString::from("Knot Downloader"),
+                service_type: ServiceType::OWN_PROCESS,
+                start_type: ServiceStartType::AutoStart,
+                error_control: ServiceErrorControl::Normal,
+                executable_path: env::current_exe()?,
+                launch_arguments: vec!["service".into(), "run".into()],
+                dependencies: Vec::new(),
+                account_name: None,
+                account_password: None,
+            };
+            let service = manager
+                .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+                .context("Failed to install service")?;
+            service.set_description("Keeps downloaded files up to date")?;
+            eventlog::register(SERVICE_NAME).context("Failed to register Event Log source")?;
+            println!("Installed service {SERVICE_NAME:?}");
+        }
+        ServiceCommand::Uninstall => {
+            let service = manager
+                .open_service(
+                    SERVICE_NAME,
+                    ServiceAccess::QUERY_STATUS | ServiceAccess::DELETE,
+                )
+                .context("Failed to open service")?;
+            service.delete().context("Failed to uninstall service")?;
+            let _ = eventlog::deregister(SERVICE_NAME);
+            println!("Uninstalled service {SERVICE_NAME:?} (it is removed once stopped)");
+        }
+        ServiceCommand::Start => {
+            manager
+                .open_service(SERVICE_NAME, ServiceAccess::START)
+                .context("Failed to open service")?
+                .start::<&OsStr>(&[])
+                .context("Failed to start service")?;
+            println!("Started service {SERVICE_NAME:?}");
+        }
+        ServiceCommand::Stop => {
+            manager
+                .open_service(SERVICE_NAME, ServiceAccess::STOP)
+                .context("Failed to open service")?
+                .stop()
+                .context("Failed to stop service")?;
+            println!("Stopped service {SERVICE_NAME:?}");
+        }
+        ServiceCommand::Run => unreachable!(),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn set_state(handle: &ServiceStatusHandle, state: ServiceState, exit_code: u32) -> Result<()> {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+
+    handle
+        .set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::ZERO,
+            process_id: None,
+        })
+        .context("Failed to set service status")
+}
+
+fn run_service() -> Result<()> {
+    let stop = Arc::new(Notify::new());
+    let handle = service_control_handler::register(SERVICE_NAME, {
+        let stop = stop.clone();
+        move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    })
+    .context("Failed to register service control handler")?;
+
+    // Services are started in the system directory, so the config is looked up next to the
+    // executable instead
+    let config_path = match env::var("CONFIG_PATH") {
+        Ok(path) => path,
+        Err(_) => env::current_exe()?
+            .with_file_name("config.yml")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let shutdown = async move {
+        stop.notified().await;
+        Ok(())
+    };
+
+    // Running is only reported once the config was loaded and logging set up, so that a service
+    // that fails to start isn't shown as running
+    set_state(&handle, ServiceState::StartPending, 0)?;
+    let (running_tx, running_rx) = oneshot::channel();
+    let running = async {
+        match running_rx.await {
+            Ok(()) => set_state(&handle, ServiceState::Running, 0),
+            Err(_) => Ok(()),
+        }
+    };
+    let (res, running) = RUNTIME
+        .get()
+        .context("Service started outside of `service run`")?
+        .block_on(async {
+            tokio::join!(
+                crate::serve(Args::default(), &config_path, shutdown, Some(running_tx)),
+                running
+            )
+        });
+    set_state(&handle, ServiceState::Stopped, u32::from(res.is_err()))?;
+
+    res.and(running)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        log::error!("{:#}", err);
+    }
+}
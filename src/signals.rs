@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+
+/// Name of the pipe that triggers a config reload on Windows when a client connects to it.
+#[cfg(windows)]
+pub const RELOAD_PIPE_NAME: &str = r"\\.\pipe\knot-downloader-reload";
+
+pub async fn wait_for_shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigterm =
+            signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+        let mut sigint =
+            signal(SignalKind::interrupt()).context("Failed to install SIGINT handler")?;
+
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = sigint.recv() => {},
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows;
+
+        let mut ctrl_c = windows::ctrl_c().context("Failed to install Ctrl-C handler")?;
+        let mut ctrl_break =
+            windows::ctrl_break().context("Failed to install Ctrl-Break handler")?;
+        let mut ctrl_close =
+            windows::ctrl_close().context("Failed to install Ctrl-Close handler")?;
+        let mut ctrl_shutdown =
+            windows::ctrl_shutdown().context("Failed to install Ctrl-Shutdown handler")?;
+
+        tokio::select! {
+            _ = ctrl_c.recv() => {},
+            _ = ctrl_break.recv() => {},
+            _ = ctrl_close.recv() => {},
+            _ = ctrl_shutdown.recv() => {},
+        }
+    }
+
+    Ok(())
+}
+
+/// Requests to reload the config: `SIGHUP` on Unix, a connection to [`RELOAD_PIPE_NAME`] on
+/// Windows.
+pub struct ReloadSignal {
+    #[cfg(unix)]
+    sighup: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    pipe: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+impl ReloadSignal {
+    pub fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            let sighup =
+                signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+            Ok(Self { sighup })
+        }
+
+        #[cfg(windows)]
+        {
+            use tokio::net::windows::named_pipe::ServerOptions;
+
+            let pipe = ServerOptions::new()
+                .first_pipe_instance(true)
+                .create(RELOAD_PIPE_NAME)
+                .with_context(|| format!("Failed to create reload pipe {RELOAD_PIPE_NAME:?}"))?;
+            Ok(Self { pipe })
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        {
+            Ok(Self {})
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            self.sighup.recv().await;
+        }
+
+        #[cfg(windows)]
+        {
+            use tokio::net::windows::named_pipe::ServerOptions;
+
+            self.pipe
+                .connect()
+                .await
+                .context("Failed to wait for reload pipe connection")?;
+            // Replacing the instance disconnects the client and waits for the next one
+            self.pipe = ServerOptions::new()
+                .create(RELOAD_PIPE_NAME)
+                .with_context(|| format!("Failed to create reload pipe {RELOAD_PIPE_NAME:?}"))?;
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        {
+            std::future::pending::<()>().await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Requests to dump the current status: `SIGUSR1` on Unix, never on other platforms.
+pub struct StatusSignal {
+    #[cfg(unix)]
+    sigusr1: tokio::signal::unix::Signal,
+}
+
+impl StatusSignal {
+    pub fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            let sigusr1 =
+                signal(SignalKind::user_defined1()).context("Failed to install SIGUSR1 handler")?;
+            Ok(Self { sigusr1 })
+        }
+
+        #[cfg(not(unix))]
+        {
+            Ok(Self {})
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            self.sigusr1.recv().await;
+        }
+
+        #[cfg(not(unix))]
+        {
+            std::future::pending::<()>().await;
+        }
+
+        Ok(())
+    }
+}
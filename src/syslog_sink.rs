@@ -0,0 +1,68 @@
+use std::{io, sync::Mutex};
+
+use log::{Level, Log, Metadata, Record};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+type SyslogLogger = Logger<LoggerBackend, Formatter3164>;
+
+/// Log sink sending records to the local syslog socket, reconnecting whenever a send fails
+/// (e.g. because the syslog daemon was restarted).
+pub struct SyslogSink {
+    logger: Mutex<Option<SyslogLogger>>,
+}
+
+fn connect() -> syslog::Result<SyslogLogger> {
+    syslog::unix(Formatter3164 {
+        facility: Facility::LOG_DAEMON,
+        hostname: None,
+        process: env!("CARGO_PKG_NAME").to_string(),
+        pid: std::process::id(),
+    })
+}
+
+impl SyslogSink {
+    pub fn new() -> io::Result<Self> {
+        let logger = connect().map_err(io::Error::other)?;
+
+        Ok(Self {
+            logger: Mutex::new(Some(logger)),
+        })
+    }
+}
+
+impl Log for SyslogSink {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        let mut logger = self.logger.lock().unwrap();
+
+        // Retry once with a fresh connection before giving up on the record
+        for _ in 0..2 {
+            if logger.is_none() {
+                *logger = connect().ok();
+            }
+            let Some(conn) = logger.as_mut() else {
+                break;
+            };
+
+            let res = match record.level() {
+                Level::Error => conn.err(&message),
+                Level::Warn => conn.warning(&message),
+                Level::Info => conn.info(&message),
+                Level::Debug | Level::Trace => conn.debug(&message),
+            };
+            if res.is_ok() {
+                return;
+            }
+
+            *logger = None;
+        }
+
+        eprintln!("Failed to send log record to syslog: {message}");
+    }
+
+    fn flush(&self) {}
+}
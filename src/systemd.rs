@@ -0,0 +1,26 @@
+#[cfg(unix)]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        log::debug!("Failed to notify systemd: {}", err);
+    }
+}
+
+/// Signals systemd (`Type=notify`) that startup has finished. Like the other notifications,
+/// this does nothing when not running under systemd or on other platforms.
+pub fn ready() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Ready);
+}
+
+pub fn stopping() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Stopping);
+}
+
+/// Keeps the service from being restarted if `WatchdogSec` is configured.
+pub fn watchdog() {
+    #[cfg(unix)]
+    if sd_notify::watchdog_enabled(false, &mut 0) {
+        notify(sd_notify::NotifyState::Watchdog);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use minijinja::Environment;
+use reqwest::{Client, Method, header};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// HTTP method, `POST` by default.
+    pub method: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    /// MiniJinja template of the request body, replacing the default JSON payload. It can use
+    /// `event`, `url`, `path`, `additions`, `removals`, `bytes`, `failures` (or
+    /// `consecutive_failures_before` for recoveries), `timestamp` and `hostname`.
+    pub template: Option<String>,
+    /// `Content-Type` of the request body, `application/json` by default.
+    pub content_type: Option<String>,
+    /// Also notifies about URLs that keep failing (see `failure_alert_after`) and their recovery.
+    pub notify_failures: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// The file was updated on disk.
+    Change,
+    Failure,
+    /// The URL was downloaded again after a notified failure.
+    Recovery,
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub url: String,
+    pub path: String,
+    pub additions: u32,
+    pub removals: u32,
+    pub bytes: usize,
+    /// Consecutive failures of the URL, or those before a recovery. `0` for changes.
+    pub failures: u32,
+}
+
+impl WebhookConfig {
+    pub fn method(&self) -> Result<Method> {
+        let method = self.method.as_deref().unwrap_or("POST");
+        Method::from_bytes(method.as_bytes())
+            .with_context(|| format!("Invalid webhook method {method:?}"))
+    }
+
+    /// Checks the syntax of the `template`.
+    pub fn validate_template(&self) -> Result<()> {
+        if let Some(template) = &self.template {
+            Environment::new()
+                .template_from_str(template)
+                .context("Invalid webhook template")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Event {
+    /// Key of `failures` in payloads, which count those before the event for recoveries.
+    fn failures_key(&self) -> &'static str {
+        match self.kind {
+            EventKind::Recovery => "consecutive_failures_before",
+            EventKind::Change | EventKind::Failure => "failures",
+        }
+    }
+}
+
+fn render(webhook: &WebhookConfig, event: &Event, timestamp: &str) -> Result<String> {
+    let Some(template) = &webhook.template else {
+        let mut payload = serde_json::json!({
+            "event": event.kind,
+            "url": event.url,
+            "path": event.path,
+            "additions": event.additions,
+            "removals": event.removals,
+            "timestamp": timestamp,
+        });
+        payload[event.failures_key()] = event.failures.into();
+        return Ok(payload.to_string());
+    };
+
+    let mut context = serde_json::json!({
+        "event": event.kind,
+        "url": event.url,
+        "path": event.path,
+        "additions": event.additions,
+        "removals": event.removals,
+        "bytes": event.bytes,
+        "timestamp": timestamp,
+        "hostname": gethostname::gethostname().to_string_lossy(),
+    });
+    context[event.failures_key()] = event.failures.into();
+    Environment::new()
+        .render_str(template, context)
+        .context("Failed to render webhook template")
+}
+
+async fn send(client: &Client, webhook: &WebhookConfig, event: &Event) -> Result<()> {
+    let timestamp = Local::now().format("%+").to_string();
+
+    let mut req = client
+        .request(webhook.method()?, &webhook.url)
+        .body(render(webhook, event, &timestamp)?);
+    let content_type = webhook.content_type.as_deref();
+    req = req.header(
+        header::CONTENT_TYPE,
+        content_type.unwrap_or("application/json"),
+    );
+    for (name, value) in webhook.headers.iter().flatten() {
+        req = req.header(name, value);
+    }
+
+    let resp = req.send().await.map_err(reqwest::Error::without_url)?;
+    resp.error_for_status()
+        .map_err(reqwest::Error::without_url)?;
+
+    Ok(())
+}
+
+/// Notifies the webhook about an event. Failures to send it are only logged.
+pub async fn notify(client: Client, webhook: WebhookConfig, event: Event) {
+    match send(&client, &webhook, &event).await {
+        Ok(()) => log::debug!("Sent webhook for {}", event.url),
+        Err(err) => log::error!("Failed to send webhook for {}: {:#}", event.url, err),
+    }
+}